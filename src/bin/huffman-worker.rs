@@ -0,0 +1,39 @@
+extern crate dotenv;
+
+use dotenv::dotenv;
+use huffman::services;
+use rocket::tokio::select;
+use rocket::tokio::signal::{self, unix::SignalKind};
+use std::env;
+
+// Resolves when the process is interrupted or asked to stop by the container runtime
+async fn shutdown_signal() {
+    let mut terminate =
+        signal::unix::signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+
+    select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+// Runs only the queue consumer, so that it can be scaled separately from the web server
+#[rocket::main]
+async fn main() -> anyhow::Result<()> {
+    // Load env variables
+    dotenv().ok();
+
+    let _logger = services::logger::initialize_standalone().await;
+
+    let mut channel = services::events::initialize().await?;
+    if let Some(concurrency) = env::var("WORKER_CONCURRENCY")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+    {
+        channel.set_concurrency(concurrency);
+    }
+
+    let pool = services::image::pool::initialize();
+    channel.listen(&pool, shutdown_signal()).await;
+    Ok(())
+}
@@ -1,7 +1,9 @@
+use anyhow::Result;
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::{
-    error::{GetObjectError, PutObjectError},
-    types::{ByteStream,SdkError},
+    error::{DeleteObjectError, GetObjectError, PutObjectError},
+    output::GetObjectOutput,
+    types::{ByteStream, SdkError},
     Client,
 };
 use rocket::http::ContentType;
@@ -12,19 +14,28 @@ pub async fn create_client() -> Client {
     Client::new(&config)
 }
 
-pub async fn fetch_object(
+pub async fn fetch_object(client: &Client, bucket_name: &str, key: &str) -> Result<Vec<u8>> {
+    let resp = get_object(client, bucket_name, key).await?;
+    read_body(resp).await
+}
+
+// Only the headers of the response have been received once this returns, so that the size of
+// the object can be checked before its body is downloaded
+pub async fn get_object(
     client: &Client,
     bucket_name: &str,
     key: &str,
-) -> Result<Vec<u8>, SdkError<GetObjectError>> {
-    let resp = client
+) -> Result<GetObjectOutput, SdkError<GetObjectError>> {
+    client
         .get_object()
         .bucket(bucket_name)
         .key(key)
         .send()
-        .await?;
+        .await
+}
 
-    let data = resp.body.collect().await.unwrap();
+pub async fn read_body(resp: GetObjectOutput) -> Result<Vec<u8>> {
+    let data = resp.body.collect().await?;
     Ok(data.into_bytes().to_vec())
 }
 
@@ -38,7 +49,7 @@ pub async fn upload_object<'a>(
     bucket_name: &str,
     key: &str,
     data: UploadData,
-) -> Result<(), PutObjectError> {
+) -> Result<(), SdkError<PutObjectError>> {
     let stream = ByteStream::from(data.body.clone());
 
     client
@@ -48,8 +59,52 @@ pub async fn upload_object<'a>(
         .body(stream)
         .content_type(data.content_type.to_string())
         .send()
-        .await
-        .unwrap();
+        .await?;
+
+    Ok(())
+}
+
+pub async fn delete_object(
+    client: &Client,
+    bucket_name: &str,
+    key: &str,
+) -> Result<(), SdkError<DeleteObjectError>> {
+    client
+        .delete_object()
+        .bucket(bucket_name)
+        .key(key)
+        .send()
+        .await?;
 
     Ok(())
 }
+
+// Lists the keys of every object under the prefix, following continuation tokens across pages
+pub async fn list_objects(client: &Client, bucket_name: &str, prefix: &str) -> Result<Vec<String>> {
+    let mut keys = vec![];
+    let mut continuation_token = None;
+
+    loop {
+        let resp = client
+            .list_objects_v2()
+            .bucket(bucket_name)
+            .prefix(prefix)
+            .set_continuation_token(continuation_token)
+            .send()
+            .await?;
+
+        keys.extend(
+            resp.contents()
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|object| object.key().map(String::from)),
+        );
+
+        match resp.next_continuation_token() {
+            Some(token) if resp.is_truncated() => continuation_token = Some(token.to_string()),
+            _ => break,
+        }
+    }
+
+    Ok(keys)
+}
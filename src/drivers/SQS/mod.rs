@@ -1,10 +1,17 @@
+use super::{Delivery, Queue};
+
+use anyhow::{anyhow, Result};
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::types::SdkError;
 use aws_sdk_sqs::{
     error::{ReceiveMessageError, SendMessageError},
+    model::{
+        DeleteMessageBatchRequestEntry, Message, MessageAttributeValue, MessageSystemAttributeName,
+        QueueAttributeName,
+    },
     Client,
 };
-use std::future::Future;
+use std::time::Duration;
 
 pub async fn create_client() -> Client {
     let region_provider = RegionProviderChain::default_provider().or_else("ap-south-1");
@@ -27,48 +34,197 @@ pub async fn send_message(
     Ok(())
 }
 
-pub async fn receive_messages<R>(
+// Long polls the queue for up to `wait_time` seconds for a batch of messages. Received messages
+// stay hidden from other consumers for `visibility_timeout` seconds.
+pub async fn receive_messages(
     client: &Client,
     queue_url: &str,
-    handler: fn(String) -> R,
-) -> Result<(), SdkError<ReceiveMessageError>>
-where
-    R: Future<Output = anyhow::Result<()>>,
-{
+    wait_time: i32,
+    visibility_timeout: i32,
+) -> Result<Vec<Message>, SdkError<ReceiveMessageError>> {
     let response = client
         .receive_message()
         .queue_url(queue_url)
         .max_number_of_messages(10)
+        .wait_time_seconds(wait_time)
+        .visibility_timeout(visibility_timeout)
+        .attribute_names(QueueAttributeName::All)
+        .send()
+        .await?;
+
+    Ok(response.messages.unwrap_or_default())
+}
+
+pub async fn delete_messages(client: &Client, queue_url: &str, receipts: &[&str]) -> Result<()> {
+    let entries = receipts
+        .iter()
+        .enumerate()
+        .map(|(index, receipt)| {
+            DeleteMessageBatchRequestEntry::builder()
+                .id(index.to_string())
+                .receipt_handle(*receipt)
+                .build()
+        })
+        .collect();
+
+    let output = client
+        .delete_message_batch()
+        .queue_url(queue_url)
+        .set_entries(Some(entries))
+        .send()
+        .await?;
+
+    for failure in output.failed().unwrap_or_default() {
+        log::error!("Could not delete message: {:?}", failure);
+    }
+
+    Ok(())
+}
+
+pub async fn change_visibility(
+    client: &Client,
+    queue_url: &str,
+    receipt: &str,
+    timeout: i32,
+) -> Result<()> {
+    client
+        .change_message_visibility()
+        .queue_url(queue_url)
+        .receipt_handle(receipt)
+        .visibility_timeout(timeout)
         .send()
         .await?;
 
-    for borrowed_message in response.messages.unwrap_or_default() {
-        let message = borrowed_message.to_owned();
-
-        if let Some(msg) = borrowed_message.body() {
-            log::info!("Received message: {:#?}", msg);
-            let res = handler(msg.to_string()).await;
-            match res {
-                Ok(_) => {
-                    if let Some(handle) = message.receipt_handle() {
-                        let delete_res = client
-                            .delete_message()
-                            .queue_url(queue_url)
-                            .receipt_handle(handle)
-                            .send()
-                            .await;
-
-                        if let Err(error) = delete_res {
-                            log::error!("{:?}", error);
-                        }
-                    }
-
-                    return Ok(());
-                }
-                Err(error) => log::error!("{:?}", error),
+    Ok(())
+}
+
+pub async fn send_dead_letter(
+    client: &Client,
+    queue_url: &str,
+    message: &str,
+    reason: &str,
+) -> Result<(), SdkError<SendMessageError>> {
+    let _ = client
+        .send_message()
+        .queue_url(queue_url)
+        .message_body(message)
+        .message_attributes(
+            "FailureReason",
+            MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(reason)
+                .build(),
+        )
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+pub struct SqsOptions {
+    pub queue_url: String,
+    pub wait_time: i32,
+    pub visibility_timeout: i32,
+    pub dead_letter_queue: Option<String>,
+}
+
+pub struct SqsQueue {
+    _client: Client,
+    _options: SqsOptions,
+}
+
+pub async fn create_queue(options: SqsOptions) -> SqsQueue {
+    SqsQueue {
+        _client: create_client().await,
+        _options: options,
+    }
+}
+
+fn to_delivery(message: Message) -> Option<Delivery> {
+    let receive_count = message
+        .attributes()
+        .and_then(|attributes| attributes.get(&MessageSystemAttributeName::ApproximateReceiveCount))
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or(1);
+
+    Some(Delivery {
+        body: message.body?,
+        receive_count,
+        receipt: message.receipt_handle?,
+    })
+}
+
+#[rocket::async_trait]
+impl Queue for SqsQueue {
+    async fn send(&self, body: &str) -> Result<()> {
+        send_message(&self._client, &self._options.queue_url, body).await?;
+        Ok(())
+    }
+
+    async fn receive(&self) -> Result<Vec<Delivery>> {
+        let messages = receive_messages(
+            &self._client,
+            &self._options.queue_url,
+            self._options.wait_time,
+            self._options.visibility_timeout,
+        )
+        .await?;
+
+        Ok(messages.into_iter().filter_map(to_delivery).collect())
+    }
+
+    async fn ack(&self, deliveries: &[Delivery]) -> Result<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let receipts: Vec<&str> = deliveries
+            .iter()
+            .map(|delivery| delivery.receipt.as_str())
+            .collect();
+        delete_messages(&self._client, &self._options.queue_url, &receipts).await
+    }
+
+    async fn nack(&self, delivery: &Delivery, delay: u32) -> Result<()> {
+        // SQS does not allow hiding a message for longer than 12 hours
+        let timeout = delay.min(43200) as i32;
+        change_visibility(
+            &self._client,
+            &self._options.queue_url,
+            &delivery.receipt,
+            timeout,
+        )
+        .await
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<()> {
+        match &self._options.dead_letter_queue {
+            Some(dead_letter_queue) => {
+                send_dead_letter(&self._client, dead_letter_queue, &delivery.body, reason)
+                    .await
+                    .map_err(|error| anyhow!("Could not dead letter message: {:?}", error))?;
             }
+            // Without a dead letter queue the message is dropped, so that it isn't retried
+            // indefinitely
+            None => log::error!("Dropping message {}. Reason: {}", delivery.body, reason),
         }
+
+        self.ack(std::slice::from_ref(delivery)).await
     }
 
-    Ok(())
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(
+            (self._options.visibility_timeout.max(2) / 2) as u64,
+        ))
+    }
+
+    async fn extend(&self, delivery: &Delivery) -> Result<()> {
+        change_visibility(
+            &self._client,
+            &self._options.queue_url,
+            &delivery.receipt,
+            self._options.visibility_timeout,
+        )
+        .await
+    }
 }
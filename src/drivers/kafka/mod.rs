@@ -1,124 +1,405 @@
+use super::{Delivery, Queue, Settlement};
+
+use anyhow::{anyhow, Result};
 use rdkafka::config::ClientConfig;
-use rdkafka::consumer::stream_consumer::StreamConsumer;
-use rdkafka::message::OwnedMessage;
-use rdkafka::producer::FutureProducer;
-use rdkafka::Message;
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::{BorrowedMessage, Header, Headers, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use rdkafka::{Message, Offset, TopicPartitionList};
+use rocket::tokio::time;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Kafka has no per message receive count, so retried messages are published again with the
+// number of the attempt in this header.
+const ATTEMPT_HEADER: &str = "huffman-attempt";
+const FAILURE_REASON_HEADER: &str = "huffman-failure-reason";
+
+const MAX_BATCH_SIZE: usize = 10;
+// Once a message has arrived, wait this long for the rest of the batch
+const BATCH_LINGER: Duration = Duration::from_millis(100);
+
+pub struct KafkaOptions {
+    pub brokers: String,
+    pub topic: String,
+    pub group_id: String,
+    pub dead_letter_topic: Option<String>,
+    pub wait_time: Duration,
+}
 
-pub async fn create_consumer() -> StreamConsumer {
-    let group_id = String::from("optimisers");
-    let brokers = String::from("localhost:9092");
+pub struct KafkaQueue {
+    _consumer: StreamConsumer,
+    _producer: FutureProducer,
+    _options: KafkaOptions,
+}
 
+pub fn create_consumer(brokers: &str, group_id: &str) -> Result<StreamConsumer> {
+    // Offsets are only stored and committed once a message has been handled
     let consumer: StreamConsumer = ClientConfig::new()
-        .set("group.id", &group_id)
-        .set("bootstrap.servers", &brokers)
+        .set("group.id", group_id)
+        .set("bootstrap.servers", brokers)
         .set("enable.partition.eof", "false")
         .set("session.timeout.ms", "6000")
         .set("enable.auto.commit", "false")
-        .create()
-        .expect("Consumer creation failed");
+        .set("enable.auto.offset.store", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
 
-    consumer
+    Ok(consumer)
 }
 
-pub async fn create_producer() -> FutureProducer {
-    let brokers = String::from("localhost:9092");
-
+pub fn create_producer(brokers: &str) -> Result<FutureProducer> {
     let producer: FutureProducer = ClientConfig::new()
-        .set("bootstrap.servers", &brokers)
+        .set("bootstrap.servers", brokers)
         .set("message.timeout.ms", "6000")
-        .create()
-        .expect("Producer creation error");
+        .create()?;
 
-    producer
+    Ok(producer)
 }
 
-pub fn extract_payload(message: OwnedMessage) -> Option<String> {
-    match message.payload_view::<str>() {
-        Some(Ok(payload)) => Some(payload.to_string()),
-        Some(Err(_)) => None,
-        None => None,
-    }
-}
+pub fn create_queue(options: KafkaOptions) -> Result<KafkaQueue> {
+    let consumer = create_consumer(&options.brokers, &options.group_id)?;
+    consumer.subscribe(&[&options.topic])?;
+    let producer = create_producer(&options.brokers)?;
 
-// Backup of code from event service. TODO: If using figure out a better way to abstract kafka 
-// related handling into this driver
+    log::info!("Subscribed to topic {}", &options.topic);
 
-pub async fn consume_events(mut shutdown: Shutdown) {
-    let consumer: StreamConsumer = kafka::create_consumer().await;
-    let topic: String = String::from("image-optimizer");
+    Ok(KafkaQueue {
+        _consumer: consumer,
+        _producer: producer,
+        _options: options,
+    })
+}
 
-    consumer
-        .subscribe(&[&topic])
-        .expect("Could not subscribe to topic: {:topic}");
+fn get_attempt(message: &BorrowedMessage<'_>) -> u32 {
+    message
+        .headers()
+        .and_then(|headers| {
+            headers
+                .iter()
+                .find(|header| header.key == ATTEMPT_HEADER)
+                .and_then(|header| header.value)
+        })
+        .and_then(|value| std::str::from_utf8(value).ok())
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(1)
+}
 
-    let stream = consumer.stream().try_for_each(|borrowed_message| {
-        let owned_message = borrowed_message.detach();
-        match extract_payload(owned_message) {
-            Some(payload) => process_event(payload),
-            None => async { Ok(()) },
+// Receipts hold the partition and offset of the message, which is all that's needed to commit it
+fn to_delivery(message: &BorrowedMessage<'_>) -> Option<Delivery> {
+    let body = match message.payload_view::<str>() {
+        Some(Ok(payload)) => payload.to_string(),
+        Some(Err(_)) | None => {
+            log::warn!("Skipping message without a valid payload");
+            return None;
         }
-    });
+    };
 
-    log::info!("Listening to events for topic :{}", topic);
+    Some(Delivery {
+        body,
+        receive_count: get_attempt(message),
+        receipt: format!("{}:{}", message.partition(), message.offset()),
+    })
+}
 
-    select! {
-        _ = stream =>
-            log::error!("Stream Failed"),
-        _ = &mut shutdown => {
-            log::error!("Consumer shutdown");
-        },
+fn parse_receipt(receipt: &str) -> Result<(i32, i64)> {
+    let (partition, offset) = receipt
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid receipt {}", receipt))?;
+
+    Ok((partition.parse()?, offset.parse()?))
+}
+
+// Offset to commit for every partition of a batch, and whether the consumer has to go back to
+// it. Offsets are committed past every settled message, but stop at the first one that
+// couldn't be settled, which is read again along with the ones after it. Handling the batch
+// out of order doesn't matter, as only the lowest unsettled offset counts.
+fn commit_offsets<'a>(
+    outcomes: impl IntoIterator<Item = (&'a Delivery, bool)>,
+) -> Result<BTreeMap<i32, (i64, bool)>> {
+    let mut offsets: BTreeMap<i32, (i64, bool)> = BTreeMap::new();
+    for (delivery, settled) in outcomes {
+        let (partition, offset) = parse_receipt(&delivery.receipt)?;
+        let commit = match offsets.get(&partition) {
+            Some((unsettled, true)) if settled || *unsettled <= offset => (*unsettled, true),
+            Some((next, false)) if settled => ((*next).max(offset + 1), false),
+            _ if settled => (offset + 1, false),
+            _ => (offset, true),
+        };
+        offsets.insert(partition, commit);
     }
+    Ok(offsets)
 }
 
-pub struct EventChannel {
-    _producer: FutureProducer,
+// Positions that the consumer group resumes from, which are the offsets of the next messages to
+// read. `store_offsets` stores them as they are, unlike `store_offset`, which adds 1 to the
+// offset of the last message that was read.
+fn positions(topic: &str, offsets: &BTreeMap<i32, (i64, bool)>) -> Result<TopicPartitionList> {
+    let mut positions = TopicPartitionList::new();
+    for (partition, (offset, _)) in offsets {
+        positions.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+    }
+    Ok(positions)
 }
 
-impl EventChannel {
-    pub async fn send(&self, message: &str) -> Result<()> {
-        let topic = String::from("image-optimizer");
-        let key = "huffman";
+impl KafkaQueue {
+    async fn publish(&self, topic: &str, body: &str, headers: OwnedHeaders) -> Result<()> {
+        let record = FutureRecord::to(topic)
+            .key("huffman")
+            .payload(body)
+            .headers(headers);
 
-        let record = FutureRecord::to(&topic).key(key).payload(message);
-        let response = self
+        match self
             ._producer
-            .send(record, Timeout::After(time::Duration::from_secs(0)));
+            .send(record, Timeout::After(Duration::from_secs(5)))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err((error, _)) => Err(anyhow!("Could not send message: {:?}", error)),
+        }
+    }
 
-        match response.await {
-            Ok(delivery) => {
-                log::info!("Sent: {:?}", delivery);
-                Ok(())
-            }
-            Err((e, _)) => {
-                log::error!("Error: {:?}", e);
-                Err(anyhow!("Could not send message"))
+    fn commit<'a>(&self, outcomes: impl IntoIterator<Item = (&'a Delivery, bool)>) -> Result<()> {
+        let topic = &self._options.topic;
+        let offsets = commit_offsets(outcomes)?;
+        self._consumer.store_offsets(&positions(topic, &offsets)?)?;
+        for (partition, (offset, unsettled)) in offsets {
+            if unsettled {
+                self._consumer.seek(
+                    topic,
+                    partition,
+                    Offset::Offset(offset),
+                    Timeout::After(Duration::from_secs(5)),
+                )?;
             }
         }
+
+        match self._consumer.commit_consumer_state(CommitMode::Async) {
+            Ok(()) => Ok(()),
+            // Nothing has been stored since the last commit
+            Err(rdkafka::error::KafkaError::ConsumerCommit(
+                rdkafka::types::RDKafkaErrorCode::NoOffset,
+            )) => Ok(()),
+            Err(error) => Err(error.into()),
+        }
     }
 }
 
-pub async fn create_channel() -> Result<EventChannel> {
-    let producer = kafka::create_producer().await;
+#[rocket::async_trait]
+impl Queue for KafkaQueue {
+    async fn send(&self, body: &str) -> Result<()> {
+        self.publish(&self._options.topic, body, OwnedHeaders::new())
+            .await
+    }
+
+    async fn receive(&self) -> Result<Vec<Delivery>> {
+        let mut deliveries = vec![];
+        let mut wait_time = self._options.wait_time;
 
-    Ok(EventChannel {
-        _producer: producer,
-    })
+        while deliveries.len() < MAX_BATCH_SIZE {
+            match time::timeout(wait_time, self._consumer.recv()).await {
+                Ok(Ok(message)) => {
+                    if let Some(delivery) = to_delivery(&message) {
+                        deliveries.push(delivery);
+                    }
+                    wait_time = BATCH_LINGER;
+                }
+                Ok(Err(error)) if deliveries.is_empty() => return Err(error.into()),
+                Ok(Err(error)) => {
+                    log::error!("{:?}", error);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        Ok(deliveries)
+    }
+
+    async fn ack(&self, deliveries: &[Delivery]) -> Result<()> {
+        self.commit(deliveries.iter().map(|delivery| (delivery, true)))
+    }
+
+    // Offsets are only committed once the whole batch is settled, as committing an offset
+    // commits every message before it in the partition
+    async fn settle(&self, outcomes: Vec<(Delivery, Settlement)>) -> Result<()> {
+        self.commit(
+            outcomes
+                .iter()
+                .map(|(delivery, settlement)| (delivery, *settlement != Settlement::Unsettled)),
+        )
+    }
+
+    // Kafka can't hide a message for a while, so a copy is published at the end of the topic
+    // right away with the next attempt number. There is no backoff between attempts.
+    async fn nack(&self, delivery: &Delivery, _delay: u32) -> Result<()> {
+        let attempt = (delivery.receive_count + 1).to_string();
+        let headers = OwnedHeaders::new().insert(Header {
+            key: ATTEMPT_HEADER,
+            value: Some(&attempt),
+        });
+
+        self.publish(&self._options.topic, &delivery.body, headers)
+            .await
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<()> {
+        match &self._options.dead_letter_topic {
+            Some(topic) => {
+                let headers = OwnedHeaders::new().insert(Header {
+                    key: FAILURE_REASON_HEADER,
+                    value: Some(reason),
+                });
+                self.publish(topic, &delivery.body, headers).await?;
+            }
+            None => log::error!("Dropping message {}. Reason: {}", delivery.body, reason),
+        }
+
+        Ok(())
+    }
 }
 
-async fn process_event(message: String) -> Result<()> {
-    let task_result = task::spawn_blocking(|| async move {
-        log::info!("Received for processing: {}", message);
-        let storage = services::storage::initialize().await.unwrap();
-        let result = services::image::generate(message.as_str(), &storage).await;
+// The tests that are ignored need a broker, which can be started with
+// `docker compose up -d kafka`. Run them with `cargo test --features kafka -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-        result
-    })
-    .await;
+    fn delivery(receipt: &str) -> Delivery {
+        Delivery {
+            body: String::from("{}"),
+            receive_count: 1,
+            receipt: receipt.to_string(),
+        }
+    }
 
-    match task_result {
-        Ok(_) => {}
-        Err(_) => {}
-    };
+    #[test]
+    fn commits_past_settled_offsets() {
+        let deliveries: Vec<Delivery> = ["0:7", "0:5", "0:6", "1:3", "1:2", "1:4", "2:9"]
+            .into_iter()
+            .map(delivery)
+            .collect();
+        // Handled out of order, with 1:2 and 1:4 failing to be nacked
+        let settled = [true, true, true, true, false, false, false];
+
+        let offsets = commit_offsets(deliveries.iter().zip(settled)).unwrap();
+        assert_eq!(
+            offsets,
+            BTreeMap::from([(0, (8, false)), (1, (2, true)), (2, (9, true))])
+        );
+        assert!(commit_offsets([(&delivery("0"), true)]).is_err());
+    }
+
+    #[test]
+    fn resumes_from_the_first_unsettled_message() {
+        let deliveries: Vec<Delivery> = ["0:5", "0:6", "0:7", "0:8", "1:3"]
+            .into_iter()
+            .map(delivery)
+            .collect();
+        // 0:7 couldn't be settled, so it is read again along with 0:8
+        let settled = [true, true, false, true, true];
+
+        let offsets = commit_offsets(deliveries.iter().zip(settled)).unwrap();
+        let positions = positions("images", &offsets).unwrap();
+        let position = |partition| {
+            positions
+                .find_partition("images", partition)
+                .map(|element| element.offset())
+        };
+        assert_eq!(position(0), Some(Offset::Offset(7)));
+        assert_eq!(position(1), Some(Offset::Offset(4)));
+        assert_eq!(position(2), None);
+    }
+
+    fn options(topic: &str) -> KafkaOptions {
+        KafkaOptions {
+            brokers: env::var("KAFKA_BROKERS").unwrap_or_else(|_| "localhost:9092".to_string()),
+            topic: topic.to_string(),
+            group_id: format!("{}-consumers", topic),
+            dead_letter_topic: None,
+            wait_time: Duration::from_secs(10),
+        }
+    }
+
+    fn unique_topic(name: &str) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        format!("huffman-test-{}-{}", name, now.as_millis())
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn commits_acknowledged_messages() {
+        let topic = unique_topic("ack");
+        let queue = create_queue(options(&topic)).unwrap();
+
+        queue.send("first").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "first");
+        assert_eq!(deliveries[0].receive_count, 1);
+
+        queue.ack(&deliveries).await.unwrap();
+        drop(queue);
 
-    Ok(())
+        // A new consumer in the same group continues after the committed offset
+        let queue = create_queue(options(&topic)).unwrap();
+        queue.send("second").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "second");
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn redelivers_nacked_messages_with_next_attempt() {
+        let topic = unique_topic("nack");
+        let queue = create_queue(options(&topic)).unwrap();
+
+        queue.send("retry me").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        let delivery = deliveries.into_iter().next().unwrap();
+        queue.nack(&delivery, 10).await.unwrap();
+        queue
+            .settle(vec![(delivery, Settlement::Released)])
+            .await
+            .unwrap();
+
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "retry me");
+        assert_eq!(deliveries[0].receive_count, 2);
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn reads_unsettled_messages_again() {
+        let topic = unique_topic("unsettled");
+        let queue = create_queue(options(&topic)).unwrap();
+
+        queue.send("first").await.unwrap();
+        queue.send("second").await.unwrap();
+        let mut deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 2);
+        let second = deliveries.pop().unwrap();
+        let first = deliveries.pop().unwrap();
+        queue
+            .settle(vec![
+                (second, Settlement::Acked),
+                (first, Settlement::Unsettled),
+            ])
+            .await
+            .unwrap();
+
+        // The acked message after the unsettled one is read again too
+        let deliveries = queue.receive().await.unwrap();
+        let bodies: Vec<&str> = deliveries
+            .iter()
+            .map(|delivery| delivery.body.as_str())
+            .collect();
+        assert_eq!(bodies, ["first", "second"]);
+        assert_eq!(deliveries[0].receive_count, 1);
+    }
 }
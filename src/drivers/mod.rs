@@ -2,3 +2,71 @@
 pub mod S3;
 #[allow(non_snake_case)]
 pub mod SQS;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "redis")]
+pub mod redis;
+
+use anyhow::Result;
+use std::time::Duration;
+
+// A message received from a queue backend. The receipt identifies the message to the backend
+// when it is acknowledged.
+pub struct Delivery {
+    pub body: String,
+    pub receive_count: u32,
+    pub receipt: String,
+}
+
+// What became of a delivery once it was handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Settlement {
+    Acked,
+    // Nacked or dead lettered, so the queue holds on to the message elsewhere
+    Released,
+    // Could not be nacked or dead lettered, so the message must be delivered again
+    Unsettled,
+}
+
+// Operations the event service needs from a message queue. Deliveries that are neither acked,
+// nacked nor dead lettered are redelivered by the backend eventually.
+#[rocket::async_trait]
+pub trait Queue: Send + Sync {
+    async fn send(&self, body: &str) -> Result<()>;
+
+    // Waits for the next batch of messages. The batch is empty when none arrived in time.
+    async fn receive(&self) -> Result<Vec<Delivery>>;
+
+    async fn ack(&self, deliveries: &[Delivery]) -> Result<()>;
+
+    // Called with every delivery of a batch once all of them have been handled. Backends that
+    // remove messages one at a time only need to ack the handled ones, as the others were
+    // already nacked or dead lettered.
+    async fn settle(&self, outcomes: Vec<(Delivery, Settlement)>) -> Result<()> {
+        let acked: Vec<Delivery> = outcomes
+            .into_iter()
+            .filter(|(_, settlement)| *settlement == Settlement::Acked)
+            .map(|(delivery, _)| delivery)
+            .collect();
+        self.ack(&acked).await
+    }
+
+    // Makes the message available to consumers again after `delay` seconds
+    async fn nack(&self, delivery: &Delivery, delay: u32) -> Result<()>;
+
+    // Removes the message from the queue, keeping a copy along with the reason if the backend
+    // has somewhere to put it.
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<()>;
+
+    // How often `extend` must be called to keep a message from being redelivered while it is
+    // being processed. None when the backend doesn't redeliver messages on a timer.
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    async fn extend(&self, _delivery: &Delivery) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use super::{Delivery, Queue};
+
+use anyhow::{anyhow, Result};
+use async_nats::jetstream::consumer::pull::{self, Batch};
+use async_nats::jetstream::consumer::Consumer;
+use async_nats::jetstream::{self, stream, AckKind, Context, Message};
+use async_nats::{Client, HeaderMap};
+use futures::StreamExt;
+use std::time::Duration;
+
+const FAILURE_REASON_HEADER: &str = "Huffman-Failure-Reason";
+
+const MAX_BATCH_SIZE: usize = 10;
+
+pub struct NatsOptions {
+    pub url: String,
+    pub stream: String,
+    pub subject: String,
+    pub consumer: String,
+    pub dead_letter_subject: Option<String>,
+    pub wait_time: Duration,
+    // Messages that haven't been acknowledged for this long are redelivered
+    pub ack_wait: Duration,
+}
+
+pub struct NatsQueue {
+    _client: Client,
+    _context: Context,
+    _consumer: Consumer<pull::Config>,
+    _options: NatsOptions,
+}
+
+pub async fn create_queue(options: NatsOptions) -> Result<NatsQueue> {
+    let client = async_nats::connect(options.url.as_str()).await?;
+    let context = jetstream::new(client.clone());
+
+    let stream = context
+        .get_or_create_stream(stream::Config {
+            name: options.stream.clone(),
+            subjects: vec![options.subject.clone()],
+            ..Default::default()
+        })
+        .await
+        .map_err(|error| anyhow!("Could not create stream: {}", error))?;
+
+    let consumer = stream
+        .get_or_create_consumer(
+            &options.consumer,
+            pull::Config {
+                durable_name: Some(options.consumer.clone()),
+                ack_wait: options.ack_wait,
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|error| anyhow!("Could not create consumer: {}", error))?;
+
+    Ok(NatsQueue {
+        _client: client,
+        _context: context,
+        _consumer: consumer,
+        _options: options,
+    })
+}
+
+// Receipts hold the reply subject of the message, which acknowledgements are published to
+fn to_delivery(message: Message) -> Option<Delivery> {
+    let receive_count = message
+        .info()
+        .map(|info| info.delivered.max(1) as u32)
+        .unwrap_or(1);
+    let receipt = message.message.reply.as_ref()?.to_string();
+
+    match String::from_utf8(message.message.payload.to_vec()) {
+        Ok(body) => Some(Delivery {
+            body,
+            receive_count,
+            receipt,
+        }),
+        Err(_) => {
+            log::warn!("Skipping message without a valid payload");
+            None
+        }
+    }
+}
+
+impl NatsQueue {
+    async fn reply(&self, delivery: &Delivery, kind: AckKind) -> Result<()> {
+        self._client
+            .publish(delivery.receipt.clone(), kind.into())
+            .await?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Queue for NatsQueue {
+    async fn send(&self, body: &str) -> Result<()> {
+        // Wait for the stream to acknowledge the message as stored
+        self._context
+            .publish(self._options.subject.clone(), body.to_string().into())
+            .await?
+            .await?;
+        Ok(())
+    }
+
+    async fn receive(&self) -> Result<Vec<Delivery>> {
+        let messages: Batch = self
+            ._consumer
+            .fetch()
+            .max_messages(MAX_BATCH_SIZE)
+            .expires(self._options.wait_time)
+            .messages()
+            .await
+            .map_err(|error| anyhow!("Could not fetch messages: {}", error))?;
+
+        let messages: Vec<_> = messages.collect().await;
+        let mut deliveries = vec![];
+        for message in messages {
+            let message = message.map_err(|error| anyhow!("{}", error))?;
+            if let Some(delivery) = to_delivery(message) {
+                deliveries.push(delivery);
+            }
+        }
+
+        Ok(deliveries)
+    }
+
+    async fn ack(&self, deliveries: &[Delivery]) -> Result<()> {
+        for delivery in deliveries {
+            self.reply(delivery, AckKind::Ack).await?;
+        }
+        self._client.flush().await?;
+        Ok(())
+    }
+
+    async fn nack(&self, delivery: &Delivery, delay: u32) -> Result<()> {
+        let delay = Duration::from_secs(delay.into());
+        self.reply(delivery, AckKind::Nak(Some(delay))).await
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<()> {
+        match &self._options.dead_letter_subject {
+            Some(subject) => {
+                let mut headers = HeaderMap::new();
+                headers.insert(FAILURE_REASON_HEADER, reason);
+                self._client
+                    .publish_with_headers(subject.clone(), headers, delivery.body.clone().into())
+                    .await?;
+            }
+            None => log::error!("Dropping message {}. Reason: {}", delivery.body, reason),
+        }
+
+        // Terminating stops the message from being redelivered
+        self.reply(delivery, AckKind::Term).await
+    }
+
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        Some(self._options.ack_wait / 2)
+    }
+
+    async fn extend(&self, delivery: &Delivery) -> Result<()> {
+        self.reply(delivery, AckKind::Progress).await
+    }
+}
+
+// These tests need a server, which can be started with `docker compose up -d nats`. Run them
+// with `cargo test --features nats -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::time;
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn options(name: &str) -> NatsOptions {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let name = format!("huffman-test-{}-{}", name, now.as_millis());
+        NatsOptions {
+            url: env::var("NATS_URL").unwrap_or_else(|_| "nats://localhost:4222".to_string()),
+            stream: name.clone(),
+            subject: format!("{}.jobs", name),
+            consumer: format!("{}-consumer", name),
+            dead_letter_subject: None,
+            wait_time: Duration::from_secs(3),
+            ack_wait: Duration::from_secs(2),
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn removes_acknowledged_messages() {
+        let queue = create_queue(options("ack")).await.unwrap();
+
+        queue.send("first").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "first");
+        assert_eq!(deliveries[0].receive_count, 1);
+
+        queue.ack(&deliveries).await.unwrap();
+        time::sleep(Duration::from_secs(3)).await;
+        assert!(queue.receive().await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn redelivers_nacked_messages_after_the_delay() {
+        let queue = create_queue(options("nack")).await.unwrap();
+
+        queue.send("retry me").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        queue.nack(&deliveries[0], 1).await.unwrap();
+
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "retry me");
+        assert_eq!(deliveries[0].receive_count, 2);
+
+        // Dead lettered messages aren't redelivered
+        queue.dead_letter(&deliveries[0], "failed").await.unwrap();
+        time::sleep(Duration::from_secs(3)).await;
+        assert!(queue.receive().await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn extending_keeps_messages_from_being_redelivered() {
+        // Waiting for less than the ack wait, so that a redelivery would arrive in time
+        let mut options = options("extend");
+        options.wait_time = Duration::from_secs(1);
+        let queue = create_queue(options).await.unwrap();
+
+        queue.send("slow").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        for _ in 0..3 {
+            time::sleep(Duration::from_secs(1)).await;
+            queue.extend(&deliveries[0]).await.unwrap();
+        }
+        queue._client.flush().await.unwrap();
+        assert!(queue.receive().await.unwrap().is_empty());
+
+        queue.ack(&deliveries).await.unwrap();
+    }
+}
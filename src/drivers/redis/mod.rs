@@ -0,0 +1,345 @@
+use super::{Delivery, Queue};
+
+use ::redis::aio::MultiplexedConnection;
+use ::redis::streams::{
+    StreamClaimOptions, StreamClaimReply, StreamId, StreamPendingCountReply, StreamReadOptions,
+    StreamReadReply,
+};
+use ::redis::{AsyncCommands, RedisResult};
+use anyhow::Result;
+use std::time::Duration;
+
+// Redis streams have no per message receive count, so retried messages are added to the stream
+// again with the number of the attempt in this field.
+const ATTEMPT_FIELD: &str = "attempt";
+const BODY_FIELD: &str = "body";
+const FAILURE_REASON_FIELD: &str = "failure_reason";
+
+const MAX_BATCH_SIZE: usize = 10;
+
+pub struct RedisOptions {
+    pub url: String,
+    pub stream: String,
+    pub group: String,
+    pub consumer: String,
+    pub dead_letter_stream: Option<String>,
+    pub wait_time: Duration,
+    // Messages that haven't been acknowledged for this long are claimed from the consumer that
+    // received them, as it has most likely gone away.
+    pub claim_timeout: Duration,
+}
+
+pub struct RedisQueue {
+    _connection: MultiplexedConnection,
+    _options: RedisOptions,
+}
+
+pub async fn create_queue(options: RedisOptions) -> Result<RedisQueue> {
+    let client = ::redis::Client::open(options.url.as_str())?;
+    let mut connection = client.get_multiplexed_async_connection().await?;
+
+    // Creating the group fails when it already exists
+    let result: RedisResult<()> = connection
+        .xgroup_create_mkstream(&options.stream, &options.group, "$")
+        .await;
+    if let Err(error) = result {
+        if error.code() != Some("BUSYGROUP") {
+            return Err(error.into());
+        }
+    }
+
+    Ok(RedisQueue {
+        _connection: connection,
+        _options: options,
+    })
+}
+
+fn to_delivery(entry: &StreamId, times_delivered: usize) -> Option<Delivery> {
+    let body = entry.get::<String>(BODY_FIELD)?;
+    let attempt = entry.get::<u32>(ATTEMPT_FIELD).unwrap_or(1);
+
+    Some(Delivery {
+        body,
+        receive_count: attempt + times_delivered.saturating_sub(1) as u32,
+        receipt: entry.id.clone(),
+    })
+}
+
+impl RedisQueue {
+    fn connection(&self) -> MultiplexedConnection {
+        self._connection.clone()
+    }
+
+    // Takes over messages that were received by another consumer but not acknowledged in time.
+    // Pending messages are filtered by idle time on the server, so that messages that are still
+    // being handled can't hide stale ones after them.
+    async fn claim_stale(&self) -> Result<Vec<Delivery>> {
+        let options = &self._options;
+        let min_idle = options.claim_timeout.as_millis() as usize;
+
+        let pending: StreamPendingCountReply = ::redis::cmd("XPENDING")
+            .arg(&options.stream)
+            .arg(&options.group)
+            .arg("IDLE")
+            .arg(min_idle)
+            .arg("-")
+            .arg("+")
+            .arg(MAX_BATCH_SIZE)
+            .query_async(&mut self.connection())
+            .await?;
+        let stale = pending.ids;
+        if stale.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let ids: Vec<&str> = stale.iter().map(|entry| entry.id.as_str()).collect();
+        let claimed: StreamClaimReply = self
+            .connection()
+            .xclaim(
+                &options.stream,
+                &options.group,
+                &options.consumer,
+                min_idle,
+                &ids,
+            )
+            .await?;
+
+        Ok(claimed
+            .ids
+            .iter()
+            .filter_map(|entry| {
+                let times_delivered = stale
+                    .iter()
+                    .find(|pending| pending.id == entry.id)
+                    .map_or(1, |pending| pending.times_delivered + 1);
+                to_delivery(entry, times_delivered)
+            })
+            .collect())
+    }
+
+    async fn add(&self, stream: &str, fields: &[(&str, &str)]) -> Result<()> {
+        let _: String = self.connection().xadd(stream, "*", fields).await?;
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl Queue for RedisQueue {
+    async fn send(&self, body: &str) -> Result<()> {
+        self.add(&self._options.stream, &[(BODY_FIELD, body)]).await
+    }
+
+    async fn receive(&self) -> Result<Vec<Delivery>> {
+        let claimed = self.claim_stale().await?;
+        if !claimed.is_empty() {
+            return Ok(claimed);
+        }
+
+        let options = &self._options;
+        let read_options = StreamReadOptions::default()
+            .group(&options.group, &options.consumer)
+            .count(MAX_BATCH_SIZE)
+            .block(options.wait_time.as_millis() as usize);
+
+        // Reading returns nil when no messages arrived while blocking
+        let reply: Option<StreamReadReply> = self
+            .connection()
+            .xread_options(&[&options.stream], &[">"], &read_options)
+            .await?;
+
+        Ok(reply
+            .map(|reply| reply.keys)
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|key| key.ids.iter())
+            .filter_map(|entry| to_delivery(entry, 1))
+            .collect())
+    }
+
+    async fn ack(&self, deliveries: &[Delivery]) -> Result<()> {
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<&str> = deliveries
+            .iter()
+            .map(|delivery| delivery.receipt.as_str())
+            .collect();
+        let mut connection = self.connection();
+        let options = &self._options;
+
+        let _: usize = connection
+            .xack(&options.stream, &options.group, &ids)
+            .await?;
+        let _: usize = connection.xdel(&options.stream, &ids).await?;
+
+        Ok(())
+    }
+
+    // Streams can't hide a message for a while, so a copy is added at the end of the stream
+    // right away with the next attempt number instead of waiting for the delay.
+    async fn nack(&self, delivery: &Delivery, _delay: u32) -> Result<()> {
+        let attempt = (delivery.receive_count + 1).to_string();
+        self.add(
+            &self._options.stream,
+            &[(BODY_FIELD, &delivery.body), (ATTEMPT_FIELD, &attempt)],
+        )
+        .await?;
+
+        self.ack(std::slice::from_ref(delivery)).await
+    }
+
+    async fn dead_letter(&self, delivery: &Delivery, reason: &str) -> Result<()> {
+        match &self._options.dead_letter_stream {
+            Some(stream) => {
+                self.add(
+                    stream,
+                    &[(BODY_FIELD, &delivery.body), (FAILURE_REASON_FIELD, reason)],
+                )
+                .await?
+            }
+            None => log::error!("Dropping message {}. Reason: {}", delivery.body, reason),
+        }
+
+        self.ack(std::slice::from_ref(delivery)).await
+    }
+
+    fn heartbeat_interval(&self) -> Option<Duration> {
+        Some(self._options.claim_timeout / 2)
+    }
+
+    // Claiming a message for the consumer that already owns it resets its idle time. Only the
+    // id is claimed, as claiming the message would count as another delivery of it.
+    async fn extend(&self, delivery: &Delivery) -> Result<()> {
+        let options = &self._options;
+        let _: Vec<String> = self
+            .connection()
+            .xclaim_options(
+                &options.stream,
+                &options.group,
+                &options.consumer,
+                0,
+                &[&delivery.receipt],
+                StreamClaimOptions::default().with_justid(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+// These tests need a server, which can be started with `docker compose up -d redis`. Run them
+// with `cargo test --features redis -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::tokio::time;
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn options(stream: &str, consumer: &str) -> RedisOptions {
+        RedisOptions {
+            url: env::var("REDIS_URL").unwrap_or_else(|_| "redis://localhost:6379".to_string()),
+            stream: stream.to_string(),
+            group: format!("{}-consumers", stream),
+            consumer: consumer.to_string(),
+            dead_letter_stream: Some(format!("{}-dead-letter", stream)),
+            wait_time: Duration::from_secs(1),
+            claim_timeout: Duration::from_secs(1),
+        }
+    }
+
+    fn unique_stream(name: &str) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        format!("huffman-test-{}-{}", name, now.as_millis())
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn removes_acknowledged_messages() {
+        let stream = unique_stream("ack");
+        let queue = create_queue(options(&stream, "a")).await.unwrap();
+
+        queue.send("first").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "first");
+        assert_eq!(deliveries[0].receive_count, 1);
+
+        queue.ack(&deliveries).await.unwrap();
+        let length: usize = queue.connection().xlen(&stream).await.unwrap();
+        assert_eq!(length, 0);
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn redelivers_nacked_messages_with_next_attempt() {
+        let stream = unique_stream("nack");
+        let queue = create_queue(options(&stream, "a")).await.unwrap();
+
+        queue.send("retry me").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        queue.nack(&deliveries[0], 10).await.unwrap();
+
+        let deliveries = queue.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, "retry me");
+        assert_eq!(deliveries[0].receive_count, 2);
+
+        queue.dead_letter(&deliveries[0], "failed").await.unwrap();
+        let length: usize = queue
+            .connection()
+            .xlen(format!("{}-dead-letter", stream))
+            .await
+            .unwrap();
+        assert_eq!(length, 1);
+        assert!(queue.receive().await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn extending_doesnt_count_as_a_delivery() {
+        let stream = unique_stream("extend");
+        let queue = create_queue(options(&stream, "a")).await.unwrap();
+
+        queue.send("slow").await.unwrap();
+        let deliveries = queue.receive().await.unwrap();
+        queue.extend(&deliveries[0]).await.unwrap();
+        queue.extend(&deliveries[0]).await.unwrap();
+        assert!(queue.receive().await.unwrap().is_empty());
+
+        // Another consumer claims the message once it hasn't been extended in time
+        time::sleep(Duration::from_millis(1500)).await;
+        let other = create_queue(options(&stream, "b")).await.unwrap();
+        let deliveries = other.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].receive_count, 2);
+    }
+
+    #[rocket::async_test]
+    #[ignore]
+    async fn claims_stale_messages_behind_a_full_batch() {
+        let stream = unique_stream("stale");
+        let queue = create_queue(options(&stream, "a")).await.unwrap();
+
+        for index in 0..=MAX_BATCH_SIZE {
+            queue.send(&format!("message {}", index)).await.unwrap();
+        }
+        let handled = queue.receive().await.unwrap();
+        assert_eq!(handled.len(), MAX_BATCH_SIZE);
+        let abandoned = queue.receive().await.unwrap();
+        assert_eq!(abandoned.len(), 1);
+
+        // The first batch is still being handled, so only the last message goes stale
+        time::sleep(Duration::from_millis(1500)).await;
+        for delivery in &handled {
+            queue.extend(delivery).await.unwrap();
+        }
+
+        let other = create_queue(options(&stream, "b")).await.unwrap();
+        let deliveries = other.receive().await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].body, abandoned[0].body);
+        assert_eq!(deliveries[0].receive_count, 2);
+    }
+}
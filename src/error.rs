@@ -0,0 +1,112 @@
+use crate::utils::http::CacheControl;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use serde::Serialize;
+use std::fmt;
+use std::io::Cursor;
+
+// Errors that are returned to clients. Each maps to a status code and a JSON body with a stable
+// error code, eg. {"error": "not_found", "message": "Could not find image a.png"}
+#[derive(Debug)]
+pub enum Error {
+    NotFound(String),
+    InvalidParameter(String),
+    UnsupportedFormat(String),
+    TooLarge(String),
+    DecodeFailed(String),
+    UpstreamUnavailable(String),
+    Unauthorized(String),
+    // Too many requests are being processed. Clients should retry after the given seconds.
+    Overloaded(u64),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+    message: String,
+}
+
+impl Error {
+    pub fn status(&self) -> Status {
+        match self {
+            Error::NotFound(_) => Status::NotFound,
+            Error::InvalidParameter(_) => Status::BadRequest,
+            Error::UnsupportedFormat(_) => Status::UnsupportedMediaType,
+            Error::TooLarge(_) => Status::UnprocessableEntity,
+            Error::DecodeFailed(_) => Status::UnprocessableEntity,
+            Error::UpstreamUnavailable(_) => Status::BadGateway,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Overloaded(_) => Status::ServiceUnavailable,
+            Error::Internal(_) => Status::InternalServerError,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::NotFound(_) => "not_found",
+            Error::InvalidParameter(_) => "invalid_parameter",
+            Error::UnsupportedFormat(_) => "unsupported_format",
+            Error::TooLarge(_) => "too_large",
+            Error::DecodeFailed(_) => "decode_failed",
+            Error::UpstreamUnavailable(_) => "upstream_unavailable",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Overloaded(_) => "overloaded",
+            Error::Internal(_) => "internal",
+        }
+    }
+
+    // Whether processing the same input again will fail the same way
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Error::NotFound(_)
+                | Error::InvalidParameter(_)
+                | Error::UnsupportedFormat(_)
+                | Error::TooLarge(_)
+                | Error::DecodeFailed(_)
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(message)
+            | Error::InvalidParameter(message)
+            | Error::UnsupportedFormat(message)
+            | Error::TooLarge(message)
+            | Error::DecodeFailed(message)
+            | Error::UpstreamUnavailable(message)
+            | Error::Unauthorized(message)
+            | Error::Internal(message) => write!(f, "{}", message),
+            Error::Overloaded(_) => write!(f, "Too many images are being processed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let body = serde_json::to_string(&ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        })
+        .map_err(|_| Status::InternalServerError)?;
+
+        let mut response = Response::build();
+        response
+            .status(self.status())
+            .header(ContentType::JSON)
+            .header(CacheControl::NoCache)
+            .sized_body(body.len(), Cursor::new(body));
+
+        if let Error::Overloaded(retry_after) = self {
+            response.header(Header::new("Retry-After", retry_after.to_string()));
+        }
+
+        Ok(response.finalize())
+    }
+}
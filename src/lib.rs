@@ -0,0 +1,4 @@
+pub mod drivers;
+pub mod error;
+pub mod services;
+pub mod utils;
@@ -2,20 +2,26 @@
 extern crate rocket;
 extern crate dotenv;
 
-mod drivers;
-mod services;
-mod utils;
-
 use dotenv::dotenv;
+use huffman::error::Error;
+use huffman::services;
+use huffman::utils::http::{CacheControl, ImageResponse, Negotiated, TextResponse, CORS};
 use rocket::fairing::AdHoc;
-use rocket::http::{ContentType, Status};
+use rocket::http::{Accept, ContentType, Status};
 use rocket::tokio::task;
 use rocket::State;
 use services::events::{message::Message, EventChannel};
+use services::image::pool::{ImagePool, PoolError};
+use services::image::signature::Signer;
+use services::image::srcset::Srcsets;
+use services::image::transform::{Negotiation, OutputFormat, Transform};
+use services::image::watermark::Watermark;
+use services::image::{format, pipeline};
 use services::storage::Storage;
+use std::collections::HashMap;
+use std::env;
 use std::path::PathBuf;
 use std::time::Instant;
-use utils::http::{CacheControl, ImageResponse, TextResponse, CORS};
 
 #[get("/ping")]
 fn ping() -> TextResponse {
@@ -23,145 +29,260 @@ fn ping() -> TextResponse {
     TextResponse::new("pong")
 }
 
-#[get("/<file..>")]
+// Rocket handlers take an argument for every guard
+#[allow(clippy::too_many_arguments)]
+#[get("/<file..>?<params..>")]
 async fn fetch(
     storage: &State<Storage>,
     channel: &State<EventChannel>,
+    pool: &State<ImagePool>,
+    negotiation: &State<Negotiation>,
+    signer: &State<Signer>,
+    accept: Option<&Accept>,
     file: PathBuf,
-) -> Option<ImageResponse> {
-    let path = file.as_os_str().to_str();
+    params: HashMap<String, String>,
+) -> Negotiated<Result<ImageResponse, Error>> {
+    // The output format only depends on the Accept header when the request doesn't set one
+    let vary = negotiation.is_enabled() && !params.contains_key("fm");
+    let negotiated = if params.contains_key("fm") {
+        None
+    } else {
+        negotiation.negotiate(accept)
+    };
+
+    Negotiated {
+        inner: fetch_image(storage, channel, pool, signer, negotiated, file, params).await,
+        vary,
+    }
+}
+
+async fn fetch_image(
+    storage: &Storage,
+    channel: &EventChannel,
+    pool: &ImagePool,
+    signer: &Signer,
+    negotiated: Option<OutputFormat>,
+    file: PathBuf,
+    params: HashMap<String, String>,
+) -> Result<ImageResponse, Error> {
+    let key = file
+        .as_os_str()
+        .to_str()
+        .ok_or_else(|| Error::NotFound(String::from("Missing path in fetch request")))?;
+    let mut transform = Transform::parse(
+        params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    )?;
+    // The signature covers the transform as requested, before the format is negotiated
+    signer.verify(key, &transform, params.get("s").map(String::as_str))?;
+    if let Some(format) = negotiated {
+        transform.format = format;
+    }
+    // Variants are rendered the same way as when they are generated from the queue
+    let variant = services::image::get_transform_variant(&transform);
+    if let Some(variant) = variant {
+        transform = services::image::get_variant_transform(variant);
+    }
     let time = Instant::now();
 
-    match path {
-        Some(key) => {
-            let file_name_without_ext = utils::get_path_without_ext(key);
-            let ext = utils::get_ext_from_path(key).unwrap_or_else(|| "png");
-            let is_allowed = utils::is_allowed_type(ext);
-
-            match is_allowed {
-                Ok(_) => {
-                    let variant_path =
-                        services::image::get_variant_path(services::image::Variants::Default);
-                    let target_path = format!("{}/{}.webp", variant_path, file_name_without_ext);
-                    let cached_image = storage.read_from_cache(&target_path).await;
-
-                    match cached_image {
-                        Ok(image) => {
-                            log::info!(
-                                "Variant found for {} at {:2?}. Returning from cache",
-                                key,
-                                time.elapsed()
-                            );
-                            Some(ImageResponse::new(
-                                image,
-                                ContentType::WEBP,
-                                CacheControl::Default,
-                            ))
-                        }
-                        Err(_error) => {
-                            let original_image = storage.read(key).await;
-
-                            match original_image {
-                                Ok(original_image) => {
-                                    let result: Result<Vec<u8>, libvips::error::Error> =
-                                        services::image::optimize(&original_image);
-
-                                    match result {
-                                        Ok(optimised_image) => {
-                                            log::info!(
-                                                "Optimised {} at {:2?}",
-                                                key,
-                                                time.elapsed()
-                                            );
-
-                                            if let Ok(_) = channel
-                                                .send_message(&Message {
-                                                    url: key.to_string(),
-                                                })
-                                                .await
-                                            {
-                                                log::info!(
-                                                    "Queued {} for caching at {:2?}",
-                                                    key,
-                                                    time.elapsed()
-                                                );
-                                            }
-
-                                            Some(ImageResponse::new(
-                                                optimised_image,
-                                                ContentType::WEBP,
-                                                CacheControl::Default,
-                                            ))
-                                        }
-                                        Err(error) => {
-                                            log::error!("Error during optimization {}", error);
-
-                                            Some(ImageResponse::new(
-                                                original_image,
-                                                ContentType::from_extension(ext)
-                                                    .unwrap_or_default(),
-                                                CacheControl::NoCache,
-                                            ))
-                                        }
-                                    }
-                                }
-                                Err(error) => {
-                                    log::warn!("Could not find image {}", error);
-                                    None
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(error) => {
-                    log::warn!("{}", error);
-                    let original_image = storage.read(key).await;
-                    match original_image {
-                        Ok(original_image) => Some(ImageResponse::new(
-                            original_image,
-                            ContentType::from_extension(ext).unwrap_or_default(),
-                            CacheControl::Default,
-                        )),
-                        Err(error) => {
-                            log::warn!("Could not find image {}", error);
-                            None
-                        }
-                    }
-                }
+    let target_path = services::image::get_transform_key(key, &transform);
+    if let Ok(image) = storage.read_from_cache(&target_path).await {
+        log::info!(
+            "Variant found for {} at {:2?}. Returning from cache",
+            key,
+            time.elapsed()
+        );
+        return Ok(ImageResponse::new(
+            image,
+            transform.format.content_type(),
+            CacheControl::Default,
+        ));
+    }
+
+    let original_image = storage.read(key, pool.limits().max_bytes).await?;
+
+    // The format is detected from the contents, as the extension in the key may be missing or
+    // wrong. Images that can't be optimized are served as they are.
+    let format = format::sniff(&original_image)
+        .ok_or_else(|| Error::UnsupportedFormat(format!("{} is not an image", key)))?;
+    if !format.is_optimizable() && transform.format == OutputFormat::Json {
+        return Err(Error::UnsupportedFormat(format!(
+            "Colors of {:?} images can't be extracted",
+            format
+        )));
+    }
+    if !format.is_optimizable() {
+        return Ok(ImageResponse::new(
+            original_image,
+            format.content_type(),
+            CacheControl::Default,
+        ));
+    }
+
+    let watermark = Watermark::load(&transform, &pool.watermarks(), storage).await?;
+
+    // The original is handed back so that it can be served when optimization fails
+    let limits = pool.limits();
+    let metadata = pool.metadata();
+    let owned_transform = transform.clone();
+    let result = pool
+        .try_run(move || {
+            let result = pipeline::render(
+                &original_image,
+                format,
+                &owned_transform,
+                &limits,
+                metadata,
+                watermark.as_ref(),
+            );
+            (original_image, result)
+        })
+        .await;
+
+    match result {
+        Ok((_, Ok(optimised_image))) => {
+            log::info!("Optimised {} at {:2?}", key, time.elapsed());
+
+            let message = if variant.is_some() {
+                Message::generate(key)
+            } else {
+                Message::transform(key, &transform)
+            };
+            if channel.send_message(&message).await.is_ok() {
+                log::info!("Queued {} for caching at {:2?}", key, time.elapsed());
             }
+
+            Ok(ImageResponse::new(
+                optimised_image,
+                transform.format.content_type(),
+                CacheControl::Default,
+            ))
+        }
+        Ok((_, Err(error @ (Error::TooLarge(_) | Error::InvalidParameter(_))))) => {
+            log::warn!("Rejected {}. {}", key, error);
+            Err(error)
         }
-        None => {
-            log::warn!("Missing path in fetch request");
-            None
+        // SVGs can run scripts, so they are only served once sanitized. Requests for colors
+        // expect JSON rather than the original.
+        Ok((_, Err(error))) if format.is_vector() || transform.format == OutputFormat::Json => {
+            log::error!("Error during rasterization {}", error);
+            Err(error)
+        }
+        Ok((original_image, Err(error))) => {
+            log::error!("Error during optimization {}", error);
+
+            Ok(ImageResponse::new(
+                original_image,
+                format.content_type(),
+                CacheControl::NoCache,
+            ))
+        }
+        Err(PoolError::Saturated) => {
+            log::warn!("Rejected {} as the pool is saturated", key);
+            Err(Error::Overloaded(pool.retry_after()))
+        }
+        Err(error) => {
+            log::error!("{}", error);
+            Err(Error::Internal(format!("Could not process {}", key)))
         }
     }
 }
 
 #[get("/generate/<file..>")]
-async fn generate(channel: &State<EventChannel>, file: PathBuf) -> Status {
-    let path = file.as_os_str().to_str();
-    match path {
-        Some(key) => {
-            match channel
-                .send_message(&Message {
-                    url: key.to_string(),
-                })
-                .await
-            {
-                Ok(_) => Status::Ok,
-                Err(error) => {
-                    log::error!("{}", error);
-                    Status::InternalServerError
-                }
-            }
-        }
-        None => {
-            log::warn!("Missing path in generate request");
-            Status::InternalServerError
+async fn generate(channel: &State<EventChannel>, file: PathBuf) -> Result<Status, Error> {
+    let key = file
+        .as_os_str()
+        .to_str()
+        .ok_or_else(|| Error::NotFound(String::from("Missing path in generate request")))?;
+
+    match channel.send_message(&Message::generate(key)).await {
+        Ok(_) => Ok(Status::Ok),
+        Err(error) => {
+            log::error!("{}", error);
+            Err(Error::UpstreamUnavailable(format!(
+                "Could not queue {}",
+                key
+            )))
         }
     }
 }
 
+#[get("/placeholder/<file..>")]
+async fn placeholder(
+    storage: &State<Storage>,
+    pool: &State<ImagePool>,
+    file: PathBuf,
+) -> Result<ImageResponse, Error> {
+    let key = file
+        .as_os_str()
+        .to_str()
+        .ok_or_else(|| Error::NotFound(String::from("Missing path in placeholder request")))?;
+
+    let placeholder = services::image::get_placeholder(key, storage, pool).await?;
+    Ok(ImageResponse::new(
+        placeholder,
+        ContentType::JSON,
+        CacheControl::Default,
+    ))
+}
+
+#[get("/meta/<file..>")]
+async fn meta(
+    storage: &State<Storage>,
+    pool: &State<ImagePool>,
+    file: PathBuf,
+) -> Result<ImageResponse, Error> {
+    let key = file
+        .as_os_str()
+        .to_str()
+        .ok_or_else(|| Error::NotFound(String::from("Missing path in meta request")))?;
+
+    let meta = services::image::get_meta(key, storage, pool).await?;
+    Ok(ImageResponse::new(
+        meta,
+        ContentType::JSON,
+        CacheControl::Default,
+    ))
+}
+
+#[get("/srcset/<file..>?<params..>")]
+fn srcset(
+    srcsets: &State<Srcsets>,
+    signer: &State<Signer>,
+    file: PathBuf,
+    params: HashMap<String, String>,
+) -> Result<ImageResponse, Error> {
+    let key = file
+        .as_os_str()
+        .to_str()
+        .ok_or_else(|| Error::NotFound(String::from("Missing path in srcset request")))?;
+
+    let is_html = services::image::srcset::is_html(params.get("output").map(String::as_str))?;
+    let srcset = srcsets.build(
+        key,
+        params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+        signer,
+    )?;
+
+    if is_html {
+        return Ok(ImageResponse::new(
+            srcset.to_html().into_bytes(),
+            ContentType::HTML,
+            CacheControl::Default,
+        ));
+    }
+    let body = serde_json::to_vec(&srcset)
+        .map_err(|error| Error::Internal(format!("Could not serialize srcset: {}", error)))?;
+    Ok(ImageResponse::new(
+        body,
+        ContentType::JSON,
+        CacheControl::Default,
+    ))
+}
+
 #[launch]
 async fn rocket() -> _ {
     // Load env variables
@@ -170,28 +291,52 @@ async fn rocket() -> _ {
     // Initialize services
     let storage: Storage = services::storage::initialize().await.unwrap();
     let channel: EventChannel = services::events::initialize().await.unwrap();
+    // Shared by requests and the embedded consumer, so that together they don't run more
+    // libvips jobs than the pool allows
+    let pool: ImagePool = services::image::pool::initialize();
+    let consumer_pool = pool.clone();
+    let negotiation = Negotiation::from_env();
+    let signer = Signer::from_env();
+    let srcsets = Srcsets::from_env();
 
     let _logger = services::logger::initialize().await;
 
+    // The consumer can run in the huffman-worker binary instead, so that generating variants
+    // doesn't compete with serving requests
+    let embedded_consumer = env::var("EMBEDDED_CONSUMER")
+        .map(|value| value != "false")
+        .unwrap_or(true);
+
     // Start server
-    rocket::build()
+    let server = rocket::build()
         .manage(storage)
         .manage(channel)
+        .manage(pool)
+        .manage(negotiation)
+        .manage(signer)
+        .manage(srcsets)
         .attach(CORS)
-        .attach(AdHoc::on_liftoff("start_consumer", |rocket| {
+        .mount("/", routes![ping])
+        .mount("/", routes![fetch])
+        .mount("/", routes![generate])
+        .mount("/", routes![placeholder])
+        .mount("/", routes![meta])
+        .mount("/", routes![srcset]);
+
+    if embedded_consumer {
+        server.attach(AdHoc::on_liftoff("start_consumer", move |rocket| {
             // Box::pin is required when spawning threads inside a fairing:
             // https://github.com/SergioBenitez/Rocket/issues/1640
             // https://github.com/SergioBenitez/Rocket/issues/1303
-            Box::pin(async {
+            Box::pin(async move {
                 let shutdown = rocket.shutdown();
-                task::spawn(async {
+                task::spawn(async move {
                     let channel: EventChannel = services::events::initialize().await.unwrap();
-                    let _ = channel.listen(shutdown).await;
-                    ()
+                    channel.listen(&consumer_pool, shutdown).await;
                 });
             })
         }))
-        .mount("/", routes![ping])
-        .mount("/", routes![fetch])
-        .mount("/", routes![generate])
+    } else {
+        server
+    }
 }
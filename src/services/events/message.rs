@@ -1,15 +1,151 @@
+use crate::services::image::transform::Transform;
+use crate::services::image::{self, Variants};
+
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::cmp::Reverse;
+
+// Bump when making a change to the schema that older consumers cannot read. Consumers reject
+// messages with a newer version instead of guessing at their contents.
+pub const VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    // Create variants that aren't already cached. An empty list of variants means all variants.
+    Generate {
+        key: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        variants: Vec<Variants>,
+        #[serde(default)]
+        priority: Priority,
+    },
+    // Create variants even when they are already cached, overwriting them, and remove the
+    // transforms, placeholder and metadata cached from the previous source.
+    Regenerate {
+        key: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        variants: Vec<Variants>,
+        #[serde(default)]
+        priority: Priority,
+    },
+    // Cache the output of a transform, given as a query string eg. `w=300&fm=avif`.
+    Transform {
+        key: String,
+        params: String,
+        #[serde(default)]
+        priority: Priority,
+    },
+    // Remove cached variants, along with every cached transform.
+    Purge {
+        key: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        variants: Vec<Variants>,
+    },
+    Batch {
+        actions: Vec<Action>,
+    },
+}
+
+impl Action {
+    pub fn variants(&self) -> Vec<Variants> {
+        match self {
+            Action::Generate { variants, .. }
+            | Action::Regenerate { variants, .. }
+            | Action::Purge { variants, .. } => {
+                if variants.is_empty() {
+                    image::all_variants()
+                } else {
+                    variants.clone()
+                }
+            }
+            Action::Transform { .. } | Action::Batch { .. } => vec![],
+        }
+    }
+
+    // Expands nested batches into a flat list of actions, ordered by priority.
+    pub fn flatten(self) -> Vec<Action> {
+        let mut actions = match self {
+            Action::Batch { actions } => actions.into_iter().flat_map(Action::flatten).collect(),
+            action => vec![action],
+        };
+        actions.sort_by_key(|action| Reverse(action.priority()));
+        actions
+    }
+
+    pub fn priority(&self) -> Priority {
+        match self {
+            Action::Generate { priority, .. }
+            | Action::Regenerate { priority, .. }
+            | Action::Transform { priority, .. } => *priority,
+            Action::Purge { .. } | Action::Batch { .. } => Priority::Normal,
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Message {
-    pub url: String,
+    pub version: u8,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+impl Message {
+    pub fn new(action: Action) -> Self {
+        Message {
+            version: VERSION,
+            action,
+        }
+    }
+
+    pub fn generate(key: &str) -> Self {
+        Message::new(Action::Generate {
+            key: key.to_string(),
+            variants: vec![],
+            priority: Priority::Normal,
+        })
+    }
+
+    pub fn transform(key: &str, transform: &Transform) -> Self {
+        Message::new(Action::Transform {
+            key: key.to_string(),
+            params: transform.to_query(),
+            priority: Priority::Normal,
+        })
+    }
+}
+
+// Messages queued before the schema was versioned only carried the url of the source image
+#[derive(Deserialize)]
+struct LegacyMessage {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Payload {
+    Versioned(Message),
+    Legacy(LegacyMessage),
 }
 
 pub fn deserialize(data: &str) -> Option<Message> {
-    let result: Result<Message, serde_json::Error> = serde_json::from_str(data);
+    let result: Result<Payload, serde_json::Error> = serde_json::from_str(data);
     match result {
-        Ok(message) => Some(message),
+        Ok(Payload::Versioned(message)) if message.version > VERSION => {
+            log::warn!("Unsupported message version {}", message.version);
+            None
+        }
+        Ok(Payload::Versioned(message)) => Some(message),
+        Ok(Payload::Legacy(legacy)) => Some(Message::generate(&legacy.url)),
         Err(error) => {
             log::warn!("{:?}", error);
             None
@@ -27,3 +163,105 @@ pub fn serialize(message: &Message) -> Option<String> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(message: Message) {
+        let data = serialize(&message).unwrap();
+        assert_eq!(deserialize(&data), Some(message));
+    }
+
+    #[test]
+    fn round_trips_every_action() {
+        round_trip(Message::generate("images/cat.png"));
+        round_trip(Message::new(Action::Regenerate {
+            key: "images/cat.png".to_string(),
+            variants: vec![Variants::Default],
+            priority: Priority::High,
+        }));
+        round_trip(Message::new(Action::Purge {
+            key: "images/cat.png".to_string(),
+            variants: vec![],
+        }));
+        round_trip(Message::new(Action::Transform {
+            key: "images/cat.gif".to_string(),
+            params: "w=300&fm=avif".to_string(),
+            priority: Priority::Low,
+        }));
+        round_trip(Message::new(Action::Batch {
+            actions: vec![
+                Action::Purge {
+                    key: "a.png".to_string(),
+                    variants: vec![],
+                },
+                Action::Batch { actions: vec![] },
+            ],
+        }));
+    }
+
+    #[test]
+    fn serializes_tagged_payload() {
+        let data = serialize(&Message::generate("cat.png")).unwrap();
+        assert_eq!(
+            data,
+            r#"{"version":1,"action":"generate","key":"cat.png","priority":"normal"}"#
+        );
+    }
+
+    #[test]
+    fn deserializes_legacy_payload() {
+        let message = deserialize(r#"{ "url": "images/cat.png" }"#).unwrap();
+        assert_eq!(message, Message::generate("images/cat.png"));
+    }
+
+    #[test]
+    fn deserializes_with_defaults() {
+        let message = deserialize(r#"{"version":1,"action":"regenerate","key":"a.png"}"#);
+        assert_eq!(
+            message.map(|message| message.action),
+            Some(Action::Regenerate {
+                key: "a.png".to_string(),
+                variants: vec![],
+                priority: Priority::Normal,
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_newer_versions_and_unknown_actions() {
+        assert_eq!(
+            deserialize(r#"{"version":2,"action":"generate","key":"a.png"}"#),
+            None
+        );
+        assert_eq!(
+            deserialize(r#"{"version":1,"action":"resize","key":"a.png"}"#),
+            None
+        );
+    }
+
+    #[test]
+    fn flattens_batches_by_priority() {
+        let low = Action::Generate {
+            key: "low.png".to_string(),
+            variants: vec![],
+            priority: Priority::Low,
+        };
+        let high = Action::Generate {
+            key: "high.png".to_string(),
+            variants: vec![],
+            priority: Priority::High,
+        };
+        let batch = Action::Batch {
+            actions: vec![
+                low.clone(),
+                Action::Batch {
+                    actions: vec![high.clone()],
+                },
+            ],
+        };
+
+        assert_eq!(batch.flatten(), vec![high, low]);
+    }
+}
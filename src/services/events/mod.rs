@@ -1,62 +1,222 @@
 pub mod message;
+pub mod notification;
+pub mod retry;
 
-use crate::drivers::SQS;
+#[cfg(feature = "kafka")]
+use crate::drivers::kafka::{self, KafkaOptions};
+#[cfg(feature = "nats")]
+use crate::drivers::nats::{self, NatsOptions};
+#[cfg(feature = "redis")]
+use crate::drivers::redis::{self, RedisOptions};
+use crate::drivers::SQS::{self, SqsOptions};
+use crate::drivers::{Delivery, Queue, Settlement};
+use crate::error::Error;
 use crate::services;
+use crate::services::image::pool::{ImagePool, PoolError};
+use crate::services::image::transform::Transform;
+use crate::services::image::Variants;
+use crate::services::storage::Storage;
+use crate::utils::{self, read_env};
 
-use self::message::Message;
+use self::message::{Action, Message, Priority};
+use self::notification::Event;
+use self::retry::{JobError, RetryPolicy};
 use anyhow::{anyhow, Result};
-use aws_sdk_s3::error::GetObjectError;
-use aws_sdk_s3::types::SdkError;
-use aws_sdk_sqs::Client;
-use rocket::tokio::{select, task, time};
-use rocket::Shutdown;
+use futures::stream::{self, StreamExt};
+use rocket::tokio::{pin, select, time};
 use std::env;
+use std::future::Future;
+
+// Seconds to wait between receive calls after the queue could not be reached
+const RECEIVE_RETRY_DELAY: u64 = 5;
 
 pub struct EventChannel {
-    _client: Client,
-    _queue: String,
-}
-
-async fn handler(data: String) -> Result<()> {
-    if let Some(message) = message::deserialize(data.as_str()) {
-        // Clone the message and spawn a blocking task to generate the variants.
-        // Cloning is necessary since value will be moved to make it thread safe.
-        let owned_message = message.clone();
-        let process = task::spawn_blocking(|| async move {
-            let storage = services::storage::initialize().await?;
-            let result = services::image::generate(&owned_message.url, &storage).await;
-            result
-        });
-
-        // Tokio's task::spawn_blocking returns a task handle which must be awaited to start task
-        // which in turn returns a promise to the inner async scope.
-        if let Ok(handle) = process.await {
-            let res = handle.await;
-            match res {
-                Ok(_) => {
-                    log::info!("Created variant for {}", &message.url);
-                    Ok(())
+    _queue: Box<dyn Queue>,
+    _concurrency: usize,
+    _retry_policy: RetryPolicy,
+}
+
+// Missing sources are skipped, as the object may have been deleted since the message was sent
+fn to_job_result(key: &str, result: Result<()>) -> Result<(), JobError> {
+    let error = match result {
+        Ok(_) => return Ok(()),
+        Err(error) => error,
+    };
+
+    if let Some(Error::NotFound(message)) = error.downcast_ref::<Error>() {
+        log::error!("Could not find source file. {}", message);
+        return Ok(());
+    }
+
+    // Images that libvips fails to decode or encode, that exceed the limits or that crash it
+    // will fail the same way again
+    let permanent = error
+        .downcast_ref::<Error>()
+        .is_some_and(|error| error.is_permanent())
+        || matches!(
+            error.downcast_ref::<PoolError>(),
+            Some(PoolError::Panicked(_))
+        );
+    let error = error.context(format!("Could not process {}", key));
+    if permanent {
+        Err(JobError::Permanent(error))
+    } else {
+        Err(JobError::Transient(error))
+    }
+}
+
+async fn generate(
+    key: &str,
+    variant: Variants,
+    force: bool,
+    storage: &Storage,
+    pool: &ImagePool,
+) -> Result<(), JobError> {
+    let result = services::image::generate(key, variant, force, storage, pool).await;
+    if result.is_ok() {
+        log::info!("Created {:?} variant for {}", variant, key);
+    }
+    to_job_result(key, result)
+}
+
+async fn process(action: Action, storage: &Storage, pool: &ImagePool) -> Result<(), JobError> {
+    let variants = action.variants();
+    let force = matches!(action, Action::Regenerate { .. });
+
+    match action {
+        Action::Generate { key, .. } | Action::Regenerate { key, .. } => {
+            // The source has changed, so the transforms, placeholder and metadata cached from it
+            // are removed and rendered again when they are next requested. Variants are
+            // overwritten instead, so that they can still be served in the meantime.
+            if force {
+                services::image::purge(&key, &[], storage)
+                    .await
+                    .map_err(|error| JobError::Transient(error.into()))?;
+            }
+            for variant in variants {
+                generate(&key, variant, force, storage, pool).await?;
+            }
+            Ok(())
+        }
+        Action::Purge { key, .. } => {
+            services::image::purge(&key, &variants, storage)
+                .await
+                .map_err(|error| JobError::Transient(error.into()))?;
+            log::info!("Purged variants for {}", &key);
+            Ok(())
+        }
+        Action::Transform { key, params, .. } => {
+            let transform = Transform::from_query(&params)
+                .map_err(|error| JobError::Permanent(error.into()))?;
+            let result =
+                services::image::generate_transform(&key, &transform, false, storage, pool).await;
+            if result.is_ok() {
+                log::info!("Created transform {} for {}", transform.to_query(), key);
+            }
+            to_job_result(&key, result)
+        }
+        // Batches are flattened before processing
+        Action::Batch { .. } => Ok(()),
+    }
+}
+
+// Converts S3 notifications for the source bucket into actions. Notifications for other buckets
+// and unsupported files are skipped. Created objects may replace existing ones, so everything
+// cached from them is regenerated.
+fn from_notification(events: Vec<Event>, is_source: impl Fn(&str) -> bool) -> Vec<Action> {
+    events
+        .into_iter()
+        .filter_map(|event| match event {
+            Event::Created { bucket, key } => {
+                if !is_source(&bucket) {
+                    log::warn!("Ignoring object created in {}: {}", bucket, key);
+                    return None;
                 }
-                Err(error) => match error.downcast_ref::<SdkError<GetObjectError>>() {
-                    Some(_) => {
-                        log::error!("Could not find source file");
-                        Ok(())
+
+                // Keys without an extension are sniffed when they are processed
+                if let Some(ext) = utils::get_ext_from_path(&key) {
+                    if let Err(error) = utils::is_allowed_type(&ext.to_lowercase()) {
+                        log::info!("Skipping {}: {}", key, error);
+                        return None;
                     }
-                    None => Err(anyhow!("Could not process {}", &message.url)),
-                },
+                }
+
+                Some(Action::Regenerate {
+                    key,
+                    variants: vec![],
+                    priority: Priority::Normal,
+                })
             }
-        } else {
-            Err(anyhow!("Could not spawn task for {}", &message.url))
-        }
+            Event::Removed { bucket, key } => {
+                if !is_source(&bucket) {
+                    log::warn!("Ignoring object removed from {}: {}", bucket, key);
+                    return None;
+                }
+
+                Some(Action::Purge {
+                    key,
+                    variants: vec![],
+                })
+            }
+        })
+        .collect()
+}
+
+async fn handle(data: String, pool: &ImagePool) -> Result<(), JobError> {
+    let storage = services::storage::initialize()
+        .await
+        .map_err(JobError::Transient)?;
+
+    // S3 notifications are delivered to the same queue when uploads to the source bucket should
+    // create variants automatically.
+    let actions = if let Some(events) = notification::parse(data.as_str()) {
+        from_notification(events, |bucket| storage.is_source(bucket))
+    } else if let Some(message) = message::deserialize(data.as_str()) {
+        message.action.flatten()
     } else {
-        Err(anyhow!("Could not deserialize message: {}", data))
+        return Err(JobError::Permanent(anyhow!(
+            "Could not deserialize message: {}",
+            data
+        )));
+    };
+
+    for action in actions {
+        process(action, &storage, pool).await?;
+    }
+
+    Ok(())
+}
+
+enum Outcome {
+    Ack,
+    // Retry after the given number of seconds
+    Retry(u32),
+    DeadLetter(String),
+}
+
+async fn handler(
+    data: String,
+    attempt: u32,
+    retry_policy: RetryPolicy,
+    pool: &ImagePool,
+) -> Outcome {
+    match handle(data, pool).await {
+        Ok(_) => Outcome::Ack,
+        Err(error) if retry_policy.should_retry(&error, attempt) => {
+            log::warn!("Attempt {} failed. {}", attempt, error);
+            Outcome::Retry(retry_policy.delay(attempt))
+        }
+        Err(error) => {
+            log::error!("Giving up after {} attempts. {}", attempt, error);
+            Outcome::DeadLetter(format!("{} (attempt {})", error, attempt))
+        }
     }
 }
 
 impl EventChannel {
     pub async fn send_message(&self, message: &Message) -> Result<()> {
         if let Some(data) = message::serialize(message) {
-            let result = SQS::send_message(&self._client, &self._queue, &data).await;
+            let result = self._queue.send(&data).await;
 
             match result {
                 Ok(_) => Ok(()),
@@ -70,19 +230,99 @@ impl EventChannel {
         }
     }
 
-    pub async fn listen(&self, mut shutdown: Shutdown) {
-        let poll_interval = env::var("SQS_POLL_INTERVAL")
-            .unwrap()
-            .parse::<u64>()
-            .unwrap();
+    // Runs the handler for a delivery, extending its visibility in the queue every heartbeat
+    // interval so that it isn't handed to another consumer while it is still being processed.
+    async fn process_delivery(&self, delivery: &Delivery, pool: &ImagePool) -> Outcome {
+        log::info!("Received message: {:#?}", delivery.body);
+
+        let work = handler(
+            delivery.body.clone(),
+            delivery.receive_count,
+            self._retry_policy,
+            pool,
+        );
+        pin!(work);
+
+        let interval = match self._queue.heartbeat_interval() {
+            Some(interval) => interval,
+            None => return work.await,
+        };
 
         loop {
             select! {
-                _ = time::sleep(time::Duration::from_secs(poll_interval)) =>
-                {
-                    log::info!("Polling for messages");
-                    let _ = SQS::receive_messages(&self._client, &self._queue, handler).await;
-                    ()
+                outcome = &mut work => break outcome,
+                _ = time::sleep(interval) => {
+                    if let Err(error) = self._queue.extend(delivery).await {
+                        log::error!("Could not extend message visibility: {:?}", error);
+                    }
+                },
+            }
+        }
+    }
+
+    // Receives a batch of messages and handles all of them, with at most `concurrency` in
+    // flight. The queue settles the whole batch once it completes, so that handled messages are
+    // acknowledged together.
+    async fn poll(&self, pool: &ImagePool) -> Result<()> {
+        let deliveries = self._queue.receive().await?;
+        if deliveries.is_empty() {
+            return Ok(());
+        }
+
+        log::info!("Received {} messages", deliveries.len());
+
+        let outcomes: Vec<(Delivery, Settlement)> = stream::iter(deliveries)
+            .map(|delivery| async move {
+                let settlement = match self.process_delivery(&delivery, pool).await {
+                    Outcome::Ack => Settlement::Acked,
+                    Outcome::Retry(delay) => {
+                        log::warn!("Retrying message in {}s: {}", delay, delivery.body);
+                        match self._queue.nack(&delivery, delay).await {
+                            Ok(()) => Settlement::Released,
+                            Err(error) => {
+                                log::error!("Could not retry message: {:?}", error);
+                                Settlement::Unsettled
+                            }
+                        }
+                    }
+                    Outcome::DeadLetter(reason) => {
+                        match self._queue.dead_letter(&delivery, &reason).await {
+                            Ok(()) => Settlement::Released,
+                            Err(error) => {
+                                log::error!("{:?}", error);
+                                Settlement::Unsettled
+                            }
+                        }
+                    }
+                };
+                (delivery, settlement)
+            })
+            .buffer_unordered(self._concurrency.max(1))
+            .collect()
+            .await;
+
+        self._queue.settle(outcomes).await
+    }
+
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        self._concurrency = concurrency;
+    }
+
+    // Handles messages until the shutdown future resolves, eg. rocket's Shutdown. Images are
+    // processed on the given pool, which may be shared with the server.
+    pub async fn listen(&self, pool: &ImagePool, shutdown: impl Future<Output = ()>) {
+        log::info!("Listening for messages");
+        pin!(shutdown);
+
+        loop {
+            // Shutting down while a batch is being processed drops it. Messages that weren't
+            // acknowledged yet are redelivered by the queue.
+            select! {
+                result = self.poll(pool) => {
+                    if let Err(error) = result {
+                        log::error!("Could not receive messages: {:?}", error);
+                        time::sleep(time::Duration::from_secs(RECEIVE_RETRY_DELAY)).await;
+                    }
                 },
                 _ = &mut shutdown => {
                     log::error!("Shutting down consumer");
@@ -93,9 +333,150 @@ impl EventChannel {
     }
 }
 
+async fn create_sqs_queue() -> Result<Box<dyn Queue>> {
+    let options = SqsOptions {
+        queue_url: env::var("SQS_URL")?,
+        // Long polling waits for up to 20 seconds for messages to arrive
+        wait_time: read_env("SQS_WAIT_TIME", 20).clamp(0, 20),
+        visibility_timeout: read_env("SQS_VISIBILITY_TIMEOUT", 60).clamp(2, 43200),
+        dead_letter_queue: env::var("SQS_DEAD_LETTER_URL").ok(),
+    };
+
+    Ok(Box::new(SQS::create_queue(options).await))
+}
+
+#[cfg(feature = "kafka")]
+fn create_kafka_queue() -> Result<Box<dyn Queue>> {
+    let options = KafkaOptions {
+        brokers: env::var("KAFKA_BROKERS")?,
+        topic: env::var("KAFKA_TOPIC")?,
+        group_id: read_env("KAFKA_GROUP_ID", String::from("huffman")),
+        dead_letter_topic: env::var("KAFKA_DEAD_LETTER_TOPIC").ok(),
+        wait_time: time::Duration::from_secs(read_env("KAFKA_WAIT_TIME", 20)),
+    };
+
+    Ok(Box::new(kafka::create_queue(options)?))
+}
+
+#[cfg(not(feature = "kafka"))]
+fn create_kafka_queue() -> Result<Box<dyn Queue>> {
+    Err(anyhow!("Huffman was built without the kafka feature"))
+}
+
+#[cfg(feature = "redis")]
+async fn create_redis_queue() -> Result<Box<dyn Queue>> {
+    let options = RedisOptions {
+        url: env::var("REDIS_URL")?,
+        stream: read_env("REDIS_STREAM", String::from("huffman")),
+        group: read_env("REDIS_GROUP", String::from("huffman")),
+        consumer: read_env("REDIS_CONSUMER", consumer_name()),
+        dead_letter_stream: env::var("REDIS_DEAD_LETTER_STREAM").ok(),
+        wait_time: time::Duration::from_secs(read_env("REDIS_WAIT_TIME", 20)),
+        claim_timeout: time::Duration::from_secs(read_env("REDIS_CLAIM_TIMEOUT", 60).max(2)),
+    };
+
+    Ok(Box::new(redis::create_queue(options).await?))
+}
+
+#[cfg(not(feature = "redis"))]
+async fn create_redis_queue() -> Result<Box<dyn Queue>> {
+    Err(anyhow!("Huffman was built without the redis feature"))
+}
+
+#[cfg(feature = "nats")]
+async fn create_nats_queue() -> Result<Box<dyn Queue>> {
+    let options = NatsOptions {
+        url: env::var("NATS_URL")?,
+        stream: read_env("NATS_STREAM", String::from("HUFFMAN")),
+        subject: read_env("NATS_SUBJECT", String::from("huffman.jobs")),
+        consumer: read_env("NATS_CONSUMER", String::from("huffman")),
+        dead_letter_subject: env::var("NATS_DEAD_LETTER_SUBJECT").ok(),
+        wait_time: time::Duration::from_secs(read_env("NATS_WAIT_TIME", 20)),
+        ack_wait: time::Duration::from_secs(read_env("NATS_ACK_WAIT", 60).max(2)),
+    };
+
+    Ok(Box::new(nats::create_queue(options).await?))
+}
+
+#[cfg(not(feature = "nats"))]
+async fn create_nats_queue() -> Result<Box<dyn Queue>> {
+    Err(anyhow!("Huffman was built without the nats feature"))
+}
+
+// Consumers in a redis group need a name that is unique to the process
+#[cfg(feature = "redis")]
+fn consumer_name() -> String {
+    let host = env::var("HOSTNAME").unwrap_or_else(|_| String::from("huffman"));
+    format!("{}-{}", host, std::process::id())
+}
+
 pub async fn initialize() -> Result<EventChannel> {
-    let _client = SQS::create_client().await;
-    let _queue = env::var("SQS_URL")?;
+    let backend = read_env("QUEUE_BACKEND", String::from("sqs"));
+    let _queue = match backend.as_str() {
+        "sqs" => create_sqs_queue().await?,
+        "kafka" => create_kafka_queue()?,
+        "redis" => create_redis_queue().await?,
+        "nats" => create_nats_queue().await?,
+        _ => return Err(anyhow!("Unsupported queue backend {}", backend)),
+    };
+
+    Ok(EventChannel {
+        _queue,
+        _concurrency: read_env("QUEUE_CONCURRENCY", 4),
+        _retry_policy: RetryPolicy::from_env(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(created: bool, bucket: &str, key: &str) -> Event {
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        if created {
+            Event::Created { bucket, key }
+        } else {
+            Event::Removed { bucket, key }
+        }
+    }
 
-    Ok(EventChannel { _client, _queue })
+    #[test]
+    fn regenerates_replaced_sources() {
+        // An upload over images/cat.png, which already has variants, replaces them
+        let actions = from_notification(
+            vec![
+                event(true, "sources", "images/cat.png"),
+                event(false, "sources", "images/dog.png"),
+            ],
+            |bucket| bucket == "sources",
+        );
+
+        assert_eq!(
+            actions,
+            [
+                Action::Regenerate {
+                    key: String::from("images/cat.png"),
+                    variants: vec![],
+                    priority: Priority::Normal,
+                },
+                Action::Purge {
+                    key: String::from("images/dog.png"),
+                    variants: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_other_buckets_and_files() {
+        let actions = from_notification(
+            vec![
+                event(true, "other", "images/cat.png"),
+                event(false, "other", "images/cat.png"),
+                event(true, "sources", "docs/report.txt"),
+            ],
+            |bucket| bucket == "sources",
+        );
+        assert!(actions.is_empty());
+    }
 }
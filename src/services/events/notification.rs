@@ -0,0 +1,251 @@
+use rocket::http::RawStr;
+use serde::Deserialize;
+use serde_json;
+
+// S3 event notification payload. Object keys are URL encoded the same way as form values.
+// https://docs.aws.amazon.com/AmazonS3/latest/userguide/notification-content-structure.html
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3Event {
+    records: Vec<S3EventRecord>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct S3EventRecord {
+    event_name: String,
+    s3: S3Entity,
+}
+
+#[derive(Deserialize)]
+struct S3Entity {
+    bucket: S3Bucket,
+    object: S3Object,
+}
+
+#[derive(Deserialize)]
+struct S3Bucket {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct S3Object {
+    key: String,
+}
+
+// Sent once by S3 when the notification configuration is saved on the bucket
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3TestEvent {
+    event: String,
+}
+
+// Envelope used when S3 notifications are fanned out through an SNS topic into the queue
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SnsEnvelope {
+    r#type: String,
+    message: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Created { bucket: String, key: String },
+    Removed { bucket: String, key: String },
+}
+
+// Returns None when the payload is not an S3 notification, so that it can be handled as a
+// regular queue message instead.
+pub fn parse(data: &str) -> Option<Vec<Event>> {
+    if let Ok(envelope) = serde_json::from_str::<SnsEnvelope>(data) {
+        if envelope.r#type == "Notification" {
+            return parse(&envelope.message);
+        }
+        return None;
+    }
+
+    if let Ok(test_event) = serde_json::from_str::<S3TestEvent>(data) {
+        if test_event.event == "s3:TestEvent" {
+            log::info!("Received S3 test event");
+            return Some(vec![]);
+        }
+        return None;
+    }
+
+    let event = serde_json::from_str::<S3Event>(data).ok()?;
+    let events = event
+        .records
+        .into_iter()
+        .filter_map(|record| {
+            let bucket = record.s3.bucket.name;
+            let key = RawStr::new(&record.s3.object.key)
+                .url_decode_lossy()
+                .into_owned();
+
+            if record.event_name.starts_with("ObjectCreated:") {
+                Some(Event::Created { bucket, key })
+            } else if record.event_name.starts_with("ObjectRemoved:") {
+                Some(Event::Removed { bucket, key })
+            } else {
+                log::warn!("Ignoring unsupported S3 event {}", record.event_name);
+                None
+            }
+        })
+        .collect();
+
+    Some(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Record as S3 sends it, trimmed of the fields that vary between requests
+    fn record(event_name: &str, key: &str) -> String {
+        format!(
+            r#"{{
+                "eventVersion": "2.1",
+                "eventSource": "aws:s3",
+                "awsRegion": "eu-west-1",
+                "eventTime": "2023-05-04T10:21:43.110Z",
+                "eventName": "{}",
+                "userIdentity": {{ "principalId": "AWS:AIDAJDPLRKLG7UEXAMPLE" }},
+                "requestParameters": {{ "sourceIPAddress": "127.0.0.1" }},
+                "responseElements": {{
+                    "x-amz-request-id": "C3D13FE58DE4C810",
+                    "x-amz-id-2": "FMyUVURIY8/IgAtTv8xRjskZQpcIZ9KG4V5Wp6S7S/JRWeUWerMUE5JgHvANOjpD"
+                }},
+                "s3": {{
+                    "s3SchemaVersion": "1.0",
+                    "configurationId": "huffman",
+                    "bucket": {{
+                        "name": "sources",
+                        "ownerIdentity": {{ "principalId": "A3NL1KOZZKExample" }},
+                        "arn": "arn:aws:s3:::sources"
+                    }},
+                    "object": {{
+                        "key": "{}",
+                        "size": 1024,
+                        "eTag": "d41d8cd98f00b204e9800998ecf8427e",
+                        "sequencer": "0055AED6DCD90281E5"
+                    }}
+                }}
+            }}"#,
+            event_name, key
+        )
+    }
+
+    fn notification(records: &[String]) -> String {
+        format!(r#"{{ "Records": [{}] }}"#, records.join(","))
+    }
+
+    fn created(key: &str) -> Event {
+        Event::Created {
+            bucket: String::from("sources"),
+            key: key.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_created_and_removed_objects() {
+        let data = notification(&[
+            record("ObjectCreated:Put", "images/cat.png"),
+            record("ObjectRemoved:Delete", "images/dog.png"),
+        ]);
+
+        assert_eq!(
+            parse(&data),
+            Some(vec![
+                created("images/cat.png"),
+                Event::Removed {
+                    bucket: String::from("sources"),
+                    key: String::from("images/dog.png"),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_keys() {
+        let data = notification(&[
+            // Spaces are encoded as `+`, and a `+` in the key as `%2B`
+            record("ObjectCreated:Put", "summer+trip/a%2Bb.jpg"),
+            record(
+                "ObjectCreated:CompleteMultipartUpload",
+                "caf%C3%A9/%E5%86%99%E7%9C%9F+%281%29.jpg",
+            ),
+        ]);
+
+        assert_eq!(
+            parse(&data),
+            Some(vec![
+                created("summer trip/a+b.jpg"),
+                created("café/写真 (1).jpg"),
+            ])
+        );
+    }
+
+    #[test]
+    fn unwraps_sns_envelopes() {
+        let message = notification(&[record("ObjectCreated:Copy", "images/cat.png")]);
+        let envelope = serde_json::json!({
+            "Type": "Notification",
+            "MessageId": "22b80b92-fdea-4c2c-8f9d-bdfb0c7bf324",
+            "TopicArn": "arn:aws:sns:eu-west-1:123456789012:huffman",
+            "Subject": "Amazon S3 Notification",
+            "Message": message,
+            "Timestamp": "2023-05-04T10:21:43.346Z",
+            "SignatureVersion": "1",
+            "Signature": "EXAMPLEpH+..",
+            "SigningCertURL": "https://sns.eu-west-1.amazonaws.com/SimpleNotificationService.pem",
+            "UnsubscribeURL": "https://sns.eu-west-1.amazonaws.com/?Action=Unsubscribe"
+        });
+
+        assert_eq!(
+            parse(&envelope.to_string()),
+            Some(vec![created("images/cat.png")])
+        );
+
+        // Confirmations of the subscription aren't notifications
+        let confirmation = serde_json::json!({
+            "Type": "SubscriptionConfirmation",
+            "Message": "You have chosen to subscribe to the topic",
+        });
+        assert_eq!(parse(&confirmation.to_string()), None);
+    }
+
+    #[test]
+    fn skips_test_events() {
+        let data = r#"{
+            "Service": "Amazon S3",
+            "Event": "s3:TestEvent",
+            "Time": "2023-05-04T10:20:01.000Z",
+            "Bucket": "sources",
+            "RequestId": "5582815E1AEA5ADF",
+            "HostId": "8cLeGAmw098X5cv4Zkwcmo8vvZa3eH3eKxsPzbB9wrR+YstdA6Knx4Ip8EXAMPLE"
+        }"#;
+
+        assert_eq!(parse(data), Some(vec![]));
+    }
+
+    #[test]
+    fn ignores_unsupported_events() {
+        let data = notification(&[
+            record("ObjectRestore:Completed", "images/cat.png"),
+            record("ObjectTagging:Put", "images/cat.png"),
+            record("ObjectCreated:Post", "images/dog.png"),
+        ]);
+
+        assert_eq!(parse(&data), Some(vec![created("images/dog.png")]));
+    }
+
+    #[test]
+    fn leaves_queue_messages_alone() {
+        assert_eq!(
+            parse(r#"{"version":1,"action":"generate","key":"a.png"}"#),
+            None
+        );
+        assert_eq!(parse(r#"{ "url": "images/cat.png" }"#), None);
+        assert_eq!(parse("not json"), None);
+    }
+}
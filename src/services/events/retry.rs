@@ -0,0 +1,105 @@
+use crate::utils::read_env;
+use std::fmt;
+
+pub enum JobError {
+    // Failures that can't succeed on retry, like a malformed payload or an image that can't be
+    // decoded. These are dead lettered right away.
+    Permanent(anyhow::Error),
+    // Failures that might go away on their own, like an unreachable bucket
+    Transient(anyhow::Error),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Permanent(error) => write!(f, "Permanent failure: {:#}", error),
+            JobError::Transient(error) => write!(f, "Transient failure: {:#}", error),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: u32,
+    pub max_delay: u32,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        RetryPolicy {
+            max_attempts: read_env("QUEUE_MAX_ATTEMPTS", 5).max(1),
+            base_delay: read_env("QUEUE_RETRY_DELAY", 10),
+            max_delay: read_env("QUEUE_RETRY_MAX_DELAY", 900),
+        }
+    }
+
+    // Exponential backoff based on the number of times the message has been received
+    pub fn delay(&self, attempt: u32) -> u32 {
+        let exponent = attempt.saturating_sub(1).min(16);
+        self.base_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay)
+    }
+
+    pub fn should_retry(&self, error: &JobError, attempt: u32) -> bool {
+        matches!(error, JobError::Transient(_)) && attempt < self.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    fn policy(max_delay: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: 10,
+            max_delay,
+        }
+    }
+
+    #[test]
+    fn doubles_the_delay_every_attempt() {
+        let delays: Vec<u32> = (1..=5).map(|attempt| policy(900).delay(attempt)).collect();
+        assert_eq!(delays, [10, 20, 40, 80, 160]);
+        // Receive counts start at 1, but a missing one is treated as the first attempt
+        assert_eq!(policy(900).delay(0), 10);
+    }
+
+    #[test]
+    fn caps_the_delay() {
+        assert_eq!(policy(900).delay(7), 640);
+        assert_eq!(policy(900).delay(8), 900);
+        assert_eq!(policy(900).delay(u32::MAX), 900);
+    }
+
+    #[test]
+    fn clamps_the_exponent() {
+        // The delay stops doubling after 16 doublings instead of overflowing the shift
+        assert_eq!(policy(u32::MAX).delay(17), 10 << 16);
+        assert_eq!(policy(u32::MAX).delay(40), 10 << 16);
+
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: u32::MAX / 2,
+            max_delay: u32::MAX,
+        };
+        assert_eq!(policy.delay(3), u32::MAX);
+    }
+
+    #[test]
+    fn retries_transient_errors_only() {
+        let policy = policy(900);
+        let transient = JobError::Transient(anyhow!("Bucket is unreachable"));
+        let permanent = JobError::Permanent(anyhow!("Image can't be decoded"));
+
+        assert!(policy.should_retry(&transient, 1));
+        assert!(policy.should_retry(&transient, 4));
+        // The fifth attempt is the last one
+        assert!(!policy.should_retry(&transient, 5));
+        assert!(!policy.should_retry(&transient, 6));
+        assert!(!policy.should_retry(&permanent, 1));
+    }
+}
@@ -0,0 +1,169 @@
+use super::transform::Transform;
+use crate::error::Error;
+use libvips::ops::{self, BandFormat, Interpretation};
+use libvips::VipsImage;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not adjust image: {}", error))
+}
+
+// Largest value of a band, as brightness and contrast are relative to it
+fn band_max(format: BandFormat) -> f64 {
+    match format {
+        BandFormat::Ushort => 65535.0,
+        _ => 255.0,
+    }
+}
+
+// Scale and offset of `linear` for brightness and contrast percentages. Contrast is scaled
+// around the middle grey.
+fn brightness_contrast(brightness: f64, contrast: f64, max: f64) -> (f64, f64) {
+    let scale = 1.0 + contrast / 100.0;
+    let offset = (max + 1.0) / 2.0 * (1.0 - scale) + brightness / 100.0 * max;
+    (scale, offset)
+}
+
+// Applies the operation to the colour bands, leaving the alpha channel as it is. The result is
+// cast back to the format of the image.
+fn on_colour(
+    image: VipsImage,
+    operation: impl FnOnce(&VipsImage, BandFormat) -> Result<VipsImage, libvips::error::Error>,
+) -> Result<VipsImage, Error> {
+    let format = image.get_format().map_err(failed)?;
+    if !image.image_hasalpha() {
+        return operation(&image, format)
+            .and_then(|image| ops::cast(&image, format))
+            .map_err(failed);
+    }
+
+    let bands = image.get_bands();
+    let colour = ops::extract_band_with_opts(&image, 0, &ops::ExtractBandOptions { n: bands - 1 })
+        .map_err(failed)?;
+    let alpha = ops::extract_band(&image, bands - 1).map_err(failed)?;
+    let adjusted = operation(&colour, format)
+        .and_then(|image| ops::cast(&image, format))
+        .map_err(failed)?;
+
+    ops::bandjoin(&mut [adjusted, alpha]).map_err(failed)
+}
+
+// `a * image + b` for every band. Casting back to the format of the image clips the result.
+fn linear(image: &VipsImage, a: f64, b: f64) -> Result<VipsImage, libvips::error::Error> {
+    let image = ops::cast(image, BandFormat::Float)?;
+    let bands = image.get_bands() as usize;
+    let scale = VipsImage::new_from_image(&image, &vec![a; bands])?;
+    let offset = VipsImage::new_from_image(&image, &vec![b; bands])?;
+
+    ops::add(&ops::multiply(&image, &scale)?, &offset)
+}
+
+// Multiplies the chroma of the image, keeping its lightness and hue
+fn saturate(image: &VipsImage, factor: f64) -> Result<VipsImage, libvips::error::Error> {
+    let lch = ops::colourspace(image, Interpretation::Lch)?;
+    let scale = VipsImage::new_from_image(&lch, &[1.0, factor, 1.0])?;
+    let saturated = ops::multiply(&lch, &scale)?;
+
+    ops::colourspace(&saturated, Interpretation::Srgb)
+}
+
+// Applies the colour and tone adjustments of the transform, followed by blurring or sharpening
+pub fn apply(image: VipsImage, transform: &Transform) -> Result<VipsImage, Error> {
+    let mut image = image;
+
+    if transform.brightness.is_some() || transform.contrast.is_some() {
+        image = on_colour(image, |image, format| {
+            let (scale, offset) = brightness_contrast(
+                transform.brightness.unwrap_or(0.0),
+                transform.contrast.unwrap_or(0.0),
+                band_max(format),
+            );
+            linear(image, scale, offset)
+        })?;
+    }
+
+    if let Some(saturation) = transform.saturation {
+        image = on_colour(image, |image, _| saturate(image, 1.0 + saturation / 100.0))?;
+    }
+
+    if let Some(gamma) = transform.gamma {
+        image = on_colour(image, |image, _| {
+            ops::gamma_with_opts(image, &ops::GammaOptions { exponent: gamma })
+        })?;
+    }
+
+    if let Some(sigma) = transform.blur {
+        image = ops::gaussblur(&image, sigma).map_err(failed)?;
+    }
+
+    if let Some(sigma) = transform.sharpen {
+        image = on_colour(image, |image, _| {
+            ops::sharpen_with_opts(
+                image,
+                &ops::SharpenOptions {
+                    sigma,
+                    ..ops::SharpenOptions::default()
+                },
+            )
+            .and_then(|sharpened| ops::colourspace(&sharpened, Interpretation::Srgb))
+        })?;
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixels(query: &str, pixels: &[u8], bands: i32) -> Vec<u8> {
+        let width = pixels.len() as i32 / bands;
+        let image = VipsImage::new_from_memory(pixels, width, 1, bands, BandFormat::Uchar).unwrap();
+        let transform = Transform::from_query(query).unwrap();
+        apply(image, &transform).unwrap().image_write_to_memory()
+    }
+
+    #[test]
+    fn offsets_brightness_and_contrast() {
+        assert_eq!(brightness_contrast(0.0, 0.0, 255.0), (1.0, 0.0));
+        assert_eq!(brightness_contrast(10.0, 0.0, 255.0), (1.0, 25.5));
+        assert_eq!(brightness_contrast(-100.0, 0.0, 65535.0), (1.0, -65535.0));
+        // The middle grey stays where it is as contrast changes
+        let (scale, offset) = brightness_contrast(0.0, 100.0, 255.0);
+        assert_eq!((scale, offset), (2.0, -128.0));
+        assert_eq!(128.0 * scale + offset, 128.0);
+        assert_eq!(brightness_contrast(0.0, -100.0, 65535.0), (0.0, 32768.0));
+
+        // Results are clipped to the range of the band
+        assert_eq!(pixels("bri=40", &[0, 100, 200], 3), [102, 202, 255]);
+        assert_eq!(pixels("con=-100", &[0, 100, 200], 3), [128, 128, 128]);
+    }
+
+    #[test]
+    fn saturates_through_lch() {
+        let color = [200, 100, 50];
+
+        // Without chroma only the lightness is left, so every channel is the same
+        let grey = pixels("sat=-100", &color, 3);
+        assert!(grey.iter().all(|value| value.abs_diff(grey[0]) <= 1));
+        assert!(grey[0] > 50 && grey[0] < 200);
+
+        // Converting to LCh and back keeps the color
+        let same = pixels("sat=1", &color, 3);
+        assert!(same.iter().zip(color).all(|(a, b)| a.abs_diff(b) <= 2));
+
+        let saturated = pixels("sat=50", &color, 3);
+        assert!(saturated[0] >= color[0] && saturated[2] <= color[2]);
+    }
+
+    #[test]
+    fn leaves_alpha_as_it_is() {
+        let rgba = [10, 200, 30, 64, 250, 20, 200, 255, 90, 90, 90, 0];
+
+        for query in ["sharp=2", "sat=100", "bri=20&con=20", "gam=2"] {
+            let output = pixels(query, &rgba, 4);
+            assert_eq!(output.len(), rgba.len(), "{}", query);
+            let alpha: Vec<u8> = output.chunks(4).map(|pixel| pixel[3]).collect();
+            assert_eq!(alpha, [64, 255, 0], "{}", query);
+        }
+    }
+}
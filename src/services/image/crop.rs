@@ -0,0 +1,74 @@
+use super::transform::Rect;
+use crate::error::Error;
+use libvips::ops::{self, BandFormat};
+use libvips::VipsImage;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not crop image: {}", error))
+}
+
+// Crops the image to the rectangle, which is in pixels of the source image. Images that were
+// shrunk while loading are cropped to the same area of the shrunk image.
+pub fn rect(image: VipsImage, rect: Rect, shrink: f64) -> Result<VipsImage, Error> {
+    let (width, height) = (image.get_width(), image.get_height());
+    let source_width = (width as f64 * shrink).round() as u64;
+    let source_height = (height as f64 * shrink).round() as u64;
+    if rect.x as u64 + rect.width as u64 > source_width
+        || rect.y as u64 + rect.height as u64 > source_height
+    {
+        return Err(Error::InvalidParameter(format!(
+            "rect {} is outside of the {}x{} image",
+            rect.name(),
+            source_width,
+            source_height
+        )));
+    }
+
+    let scale = |value: u32| (value as f64 / shrink).round() as i32;
+    let x = scale(rect.x).min(width - 1);
+    let y = scale(rect.y).min(height - 1);
+    ops::extract_area(
+        &image,
+        x,
+        y,
+        scale(rect.width).clamp(1, width - x),
+        scale(rect.height).clamp(1, height - y),
+    )
+    .map_err(failed)
+}
+
+// Value of every band of the top left pixel
+fn corner(image: &VipsImage) -> Result<Vec<f64>, Error> {
+    let pixel = ops::extract_area(image, 0, 0, 1, 1)
+        .and_then(|pixel| ops::cast(&pixel, BandFormat::Double))
+        .map_err(failed)?;
+
+    Ok(pixel
+        .image_write_to_memory()
+        .chunks_exact(8)
+        .map(|bytes| f64::from_ne_bytes(bytes.try_into().unwrap_or([0; 8])))
+        .collect())
+}
+
+// Removes the borders that have the color of the top left pixel. They are found on the first
+// frame, so that every frame keeps the same size. Images that are all border are kept as they
+// are.
+pub fn trim(frames: Vec<VipsImage>, threshold: u32) -> Result<Vec<VipsImage>, Error> {
+    let (left, top, width, height) = ops::find_trim_with_opts(
+        &frames[0],
+        &ops::FindTrimOptions {
+            threshold: threshold as f64,
+            background: corner(&frames[0])?,
+        },
+    )
+    .map_err(failed)?;
+    if width <= 0 || height <= 0 {
+        return Ok(frames);
+    }
+
+    frames
+        .into_iter()
+        .map(|frame| ops::extract_area(&frame, left, top, width, height))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(failed)
+}
@@ -0,0 +1,421 @@
+use super::format::Format;
+use super::transform::OutputFormat;
+
+// EXIF tag of the orientation, from 1 for upright images to 8
+const ORIENTATION_TAG: u16 = 0x0112;
+// EXIF tags that say who made the image and who owns it
+const ARTIST_TAG: u16 = 0x013B;
+const COPYRIGHT_TAG: u16 = 0x8298;
+// Type of EXIF values that are NUL terminated text
+const ASCII_TYPE: u16 = 2;
+
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+fn read_u16(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes: [u8; 2] = bytes.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_u32(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes: [u8; 4] = bytes.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+// Offset of the entry for the tag in the first IFD of EXIF data, which is laid out as a TIFF
+// file, along with whether the data is little endian
+fn find_entry(tiff: &[u8], tag: u16) -> Option<(usize, bool)> {
+    let little_endian = match tiff.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    if read_u16(tiff, 2, little_endian)? != 42 {
+        return None;
+    }
+
+    let ifd = read_u32(tiff, 4, little_endian)? as usize;
+    let entries = read_u16(tiff, ifd, little_endian)? as usize;
+    (0..entries)
+        .map(|index| ifd + 2 + index * 12)
+        .find(|entry| read_u16(tiff, *entry, little_endian) == Some(tag))
+        .map(|entry| (entry, little_endian))
+}
+
+pub fn orientation(tiff: &[u8]) -> Option<u8> {
+    let (entry, little_endian) = find_entry(tiff, ORIENTATION_TAG)?;
+    read_u16(tiff, entry + 8, little_endian)
+        .and_then(|orientation| u8::try_from(orientation).ok())
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+// Text of the entry for the tag, without the NUL. Values of up to 4 bytes are kept in the entry
+// itself, longer ones are pointed to.
+fn ascii(tiff: &[u8], tag: u16) -> Option<Vec<u8>> {
+    let (entry, little_endian) = find_entry(tiff, tag)?;
+    if read_u16(tiff, entry + 2, little_endian)? != ASCII_TYPE {
+        return None;
+    }
+
+    let count = read_u32(tiff, entry + 4, little_endian)? as usize;
+    let offset = if count <= 4 {
+        entry + 8
+    } else {
+        read_u32(tiff, entry + 8, little_endian)? as usize
+    };
+    let value = tiff.get(offset..offset.checked_add(count)?)?;
+    let end = value
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(value.len());
+    Some(value[..end].to_vec()).filter(|value| !value.is_empty())
+}
+
+// EXIF data of JPEGs is in an APP1 segment, of PNGs in an eXIf chunk and of WebPs in an EXIF
+// chunk. Other formats either don't have it or are read upright by libvips.
+pub fn find(buffer: &[u8], format: Format) -> Option<&[u8]> {
+    match format {
+        Format::Jpeg => {
+            let mut offset = 2;
+            while buffer.get(offset) == Some(&0xFF) {
+                let marker = *buffer.get(offset + 1)?;
+                // Pixels start after the start of scan segment
+                if marker == 0xDA {
+                    return None;
+                }
+                let length = read_u16(buffer, offset + 2, false)? as usize;
+                let segment = buffer.get(offset + 4..offset + 2 + length)?;
+                if marker == 0xE1 {
+                    if let Some(tiff) = segment.strip_prefix(EXIF_PREFIX) {
+                        return Some(tiff);
+                    }
+                }
+                offset += 2 + length;
+            }
+            None
+        }
+        Format::Png => {
+            let mut offset = 8;
+            while let Some(length) = read_u32(buffer, offset, false) {
+                let kind = buffer.get(offset + 4..offset + 8)?;
+                let data = buffer.get(offset + 8..offset + 8 + length as usize)?;
+                match kind {
+                    b"eXIf" => return Some(data),
+                    b"IDAT" | b"IEND" => return None,
+                    _ => offset += 12 + length as usize,
+                }
+            }
+            None
+        }
+        Format::Webp => {
+            let mut offset = 12;
+            while let Some(length) = read_u32(buffer, offset + 4, true) {
+                let kind = buffer.get(offset..offset + 4)?;
+                let data = buffer.get(offset + 8..offset + 8 + length as usize)?;
+                if kind == b"EXIF" {
+                    return Some(data.strip_prefix(EXIF_PREFIX).unwrap_or(data));
+                }
+                // Chunks are padded to an even length
+                offset += 8 + length as usize + (length & 1) as usize;
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+// EXIF data with only the artist and copyright of the source image, or none when it has
+// neither. Everything else, eg. GPS coordinates, is left out.
+pub fn copyright(buffer: &[u8], format: Format) -> Option<Vec<u8>> {
+    let tiff = find(buffer, format)?;
+    let fields: Vec<(u16, Vec<u8>)> = [ARTIST_TAG, COPYRIGHT_TAG]
+        .into_iter()
+        .filter_map(|tag| ascii(tiff, tag).map(|value| (tag, value)))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    // Values that don't fit in their entry follow the IFD, at even offsets
+    let values_offset = 8 + 2 + fields.len() * 12 + 4;
+    let mut exif = b"MM\0\x2a\0\0\0\x08".to_vec();
+    let mut values = vec![];
+    exif.extend((fields.len() as u16).to_be_bytes());
+    for (tag, value) in &fields {
+        let mut value = value.clone();
+        value.push(0);
+        exif.extend(tag.to_be_bytes());
+        exif.extend(ASCII_TYPE.to_be_bytes());
+        exif.extend((value.len() as u32).to_be_bytes());
+        if value.len() <= 4 {
+            value.resize(4, 0);
+            exif.extend(value);
+        } else {
+            exif.extend(((values_offset + values.len()) as u32).to_be_bytes());
+            values.extend(&value);
+            values.resize(values.len() + values.len() % 2, 0);
+        }
+    }
+    exif.extend([0; 4]);
+    exif.extend(values);
+    Some(exif)
+}
+
+fn jpeg_with_exif(jpeg: &[u8], tiff: &[u8]) -> Option<Vec<u8>> {
+    let length = u16::try_from(2 + EXIF_PREFIX.len() + tiff.len()).ok()?;
+    // The segment goes after the JFIF segment, which has to come first
+    let mut offset = 2;
+    if jpeg.get(2..4)? == [0xFF, 0xE0] {
+        offset += 2 + read_u16(jpeg, 4, false)? as usize;
+    }
+
+    let mut output = jpeg.get(..offset)?.to_vec();
+    output.extend([0xFF, 0xE1]);
+    output.extend(length.to_be_bytes());
+    output.extend(EXIF_PREFIX);
+    output.extend(tiff);
+    output.extend(&jpeg[offset..]);
+    Some(output)
+}
+
+fn png_with_exif(png: &[u8], tiff: &[u8]) -> Option<Vec<u8>> {
+    // The chunk goes right after the header chunk, which is always 13 bytes long
+    let offset = 8 + 12 + 13;
+    let mut chunk = b"eXIf".to_vec();
+    chunk.extend(tiff);
+    let crc = crc32fast::hash(&chunk);
+
+    let mut output = png.get(..offset)?.to_vec();
+    output.extend((tiff.len() as u32).to_be_bytes());
+    output.extend(chunk);
+    output.extend(crc.to_be_bytes());
+    output.extend(&png[offset..]);
+    Some(output)
+}
+
+// Simple WebPs only have a single chunk with the image. Extended ones start with a VP8X chunk,
+// which flags the EXIF chunk at the end.
+fn webp_with_exif(webp: &[u8], tiff: &[u8]) -> Option<Vec<u8>> {
+    let chunks = webp.get(12..)?;
+    let mut output = webp[..12].to_vec();
+    match chunks.get(..4)? {
+        b"VP8X" => {
+            output.extend(chunks);
+            *output.get_mut(20)? |= 0x08;
+        }
+        kind @ (b"VP8 " | b"VP8L") => {
+            let data = chunks.get(8..)?;
+            let (width, height, alpha) = if kind == b"VP8 " {
+                (
+                    (read_u16(data, 6, true)? & 0x3FFF) as u32,
+                    (read_u16(data, 8, true)? & 0x3FFF) as u32,
+                    false,
+                )
+            } else {
+                let bits = read_u32(data, 1, true)?;
+                (
+                    (bits & 0x3FFF) + 1,
+                    (bits >> 14 & 0x3FFF) + 1,
+                    bits >> 28 & 1 == 1,
+                )
+            };
+            output.extend(b"VP8X");
+            output.extend(10u32.to_le_bytes());
+            output.extend([if alpha { 0x18 } else { 0x08 }, 0, 0, 0]);
+            output.extend(&(width.max(1) - 1).to_le_bytes()[..3]);
+            output.extend(&(height.max(1) - 1).to_le_bytes()[..3]);
+            output.extend(chunks);
+        }
+        _ => return None,
+    }
+
+    output.extend(b"EXIF");
+    output.extend((tiff.len() as u32).to_le_bytes());
+    output.extend(tiff);
+    output.resize(output.len() + tiff.len() % 2, 0);
+    let size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&size.to_le_bytes());
+    Some(output)
+}
+
+// Adds EXIF data to an encoded image. AVIF and JPEG XL images are left as they are, as their
+// metadata is kept in boxes that the encoder lays out.
+pub fn insert(image: Vec<u8>, format: OutputFormat, tiff: &[u8]) -> Vec<u8> {
+    let output = match format {
+        OutputFormat::Jpeg => jpeg_with_exif(&image, tiff),
+        OutputFormat::Png => png_with_exif(&image, tiff),
+        OutputFormat::Webp => webp_with_exif(&image, tiff),
+        _ => None,
+    };
+    output.unwrap_or(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // EXIF data with an orientation, followed by the given text fields
+    fn tiff(orientation: u16, little_endian: bool, text: &[(u16, &str)]) -> Vec<u8> {
+        let u16_bytes = |value: u16| {
+            if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        };
+        let u32_bytes = |value: u32| {
+            if little_endian {
+                value.to_le_bytes()
+            } else {
+                value.to_be_bytes()
+            }
+        };
+
+        let mut tiff = if little_endian {
+            b"II".to_vec()
+        } else {
+            b"MM".to_vec()
+        };
+        tiff.extend(u16_bytes(42));
+        tiff.extend(u32_bytes(8));
+        tiff.extend(u16_bytes(1 + text.len() as u16));
+        tiff.extend(u16_bytes(ORIENTATION_TAG));
+        tiff.extend(u16_bytes(3));
+        tiff.extend(u32_bytes(1));
+        tiff.extend(u16_bytes(orientation));
+        tiff.extend([0, 0]);
+
+        let mut offset = 8 + 2 + (1 + text.len()) * 12 + 4;
+        let mut values = vec![];
+        for (tag, value) in text {
+            tiff.extend(u16_bytes(*tag));
+            tiff.extend(u16_bytes(ASCII_TYPE));
+            tiff.extend(u32_bytes(value.len() as u32 + 1));
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                tiff.extend(value);
+            } else {
+                tiff.extend(u32_bytes(offset as u32));
+                offset += value.len();
+                values.extend(value);
+            }
+        }
+        tiff.extend([0; 4]);
+        tiff.extend(values);
+        tiff
+    }
+
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let exif_segment = [EXIF_PREFIX, tiff].concat();
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0, 0xFF, 0xE1];
+        jpeg.extend((exif_segment.len() as u16 + 2).to_be_bytes());
+        jpeg.extend(&exif_segment);
+        jpeg.extend([0xFF, 0xDA]);
+        jpeg
+    }
+
+    #[test]
+    fn reads_exif_orientation() {
+        assert_eq!(orientation(&tiff(6, true, &[])), Some(6));
+        assert_eq!(orientation(&tiff(8, false, &[])), Some(8));
+        assert_eq!(orientation(&tiff(9, true, &[])), None);
+        assert_eq!(orientation(b"II*"), None);
+
+        assert_eq!(
+            find(&jpeg(&tiff(3, false, &[])), Format::Jpeg).and_then(orientation),
+            Some(3)
+        );
+
+        let data = tiff(5, true, &[]);
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend((data.len() as u32).to_be_bytes());
+        png.extend(b"eXIf");
+        png.extend(&data);
+        png.extend([0; 4]);
+        assert_eq!(find(&png, Format::Png).and_then(orientation), Some(5));
+
+        let mut webp = b"RIFF\0\0\0\0WEBP".to_vec();
+        webp.extend(b"EXIF");
+        webp.extend((data.len() as u32).to_le_bytes());
+        webp.extend(&data);
+        assert_eq!(find(&webp, Format::Webp).and_then(orientation), Some(5));
+
+        assert_eq!(find(&[0xFF, 0xD8, 0xFF, 0xDA], Format::Jpeg), None);
+    }
+
+    #[test]
+    fn keeps_only_the_artist_and_copyright() {
+        let source = jpeg(&tiff(
+            6,
+            true,
+            &[
+                (0x010E, "Holiday at the beach"),
+                (ARTIST_TAG, "Jane Doe"),
+                (COPYRIGHT_TAG, "(c) 2023 Jane Doe"),
+            ],
+        ));
+
+        let exif = copyright(&source, Format::Jpeg).unwrap();
+        assert_eq!(ascii(&exif, ARTIST_TAG).unwrap(), b"Jane Doe");
+        assert_eq!(ascii(&exif, COPYRIGHT_TAG).unwrap(), b"(c) 2023 Jane Doe");
+        assert_eq!(ascii(&exif, 0x010E), None);
+        // The output is already upright
+        assert_eq!(orientation(&exif), None);
+
+        // Values of up to 4 bytes are kept in their entry
+        let source = jpeg(&tiff(1, false, &[(ARTIST_TAG, "Ann")]));
+        let exif = copyright(&source, Format::Jpeg).unwrap();
+        assert_eq!(ascii(&exif, ARTIST_TAG).unwrap(), b"Ann");
+        assert_eq!(ascii(&exif, COPYRIGHT_TAG), None);
+
+        assert_eq!(copyright(&jpeg(&tiff(1, true, &[])), Format::Jpeg), None);
+    }
+
+    #[test]
+    fn inserts_exif_into_encoded_images() {
+        let exif = tiff(1, false, &[(COPYRIGHT_TAG, "(c) Jane Doe")]);
+        let read = |image: &[u8], format: Format| {
+            find(image, format).and_then(|tiff| ascii(tiff, COPYRIGHT_TAG))
+        };
+
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0, 0xFF, 0xDA].to_vec();
+        let output = insert(jpeg, OutputFormat::Jpeg, &exif);
+        assert_eq!(&output[..8], [0xFF, 0xD8, 0xFF, 0xE0, 0, 4, 0, 0]);
+        assert_eq!(read(&output, Format::Jpeg).unwrap(), b"(c) Jane Doe");
+
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend([0; 17]);
+        png.extend(b"\0\0\0\0IDAT\0\0\0\0");
+        let output = insert(png, OutputFormat::Png, &exif);
+        assert_eq!(read(&output, Format::Png).unwrap(), b"(c) Jane Doe");
+        let crc = &output[41 + exif.len()..45 + exif.len()];
+        assert_eq!(
+            crc,
+            crc32fast::hash(&[b"eXIf".as_slice(), &exif].concat()).to_be_bytes()
+        );
+
+        // Simple lossless WebP of 300x200 pixels with alpha
+        let bits: u32 = 299 | 199 << 14 | 1 << 28;
+        let mut webp = b"RIFF\x16\0\0\0WEBPVP8L\x0a\0\0\0\x2f".to_vec();
+        webp.extend(bits.to_le_bytes());
+        webp.extend([0; 5]);
+        let output = insert(webp, OutputFormat::Webp, &exif);
+        assert_eq!(&output[12..20], b"VP8X\x0a\0\0\0");
+        assert_eq!(output[20], 0x18);
+        assert_eq!(&output[24..30], [43, 1, 0, 199, 0, 0]);
+        assert_eq!(read_u32(&output, 4, true), Some(output.len() as u32 - 8));
+        assert_eq!(read(&output, Format::Webp).unwrap(), b"(c) Jane Doe");
+
+        // Other formats are left as they are
+        assert_eq!(insert(vec![1, 2, 3], OutputFormat::Avif, &exif), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,198 @@
+use rocket::http::ContentType;
+
+// Formats recognised from the first bytes of a file. Extensions in keys aren't trusted, so
+// files are only served or processed when they are one of these.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+    Heif,
+    Jxl,
+    Gif,
+    Tiff,
+    Svg,
+    Pdf,
+}
+
+// Brands of HEIF images and sequences, eg. HEIC photos from iPhones
+const HEIF_BRANDS: [&[u8]; 8] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+// JPEG XL files are either a bare codestream or a container made of ISO base media boxes
+const JXL_CODESTREAM: &[u8] = b"\xFF\x0A";
+const JXL_CONTAINER: &[u8] = b"\0\0\0\x0CJXL \r\n\x87\n";
+
+// Bytes searched for the root element of SVG files, which can start with an XML declaration,
+// comments or a doctype
+const SVG_SNIFF_LENGTH: usize = 1024;
+
+impl Format {
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Format::Jpeg => ContentType::JPEG,
+            Format::Png => ContentType::PNG,
+            Format::Webp => ContentType::WEBP,
+            Format::Avif => ContentType::AVIF,
+            Format::Heif => ContentType::new("image", "heif"),
+            Format::Jxl => ContentType::new("image", "jxl"),
+            Format::Gif => ContentType::GIF,
+            Format::Tiff => ContentType::TIFF,
+            Format::Svg => ContentType::SVG,
+            Format::Pdf => ContentType::PDF,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpeg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+            Format::Heif => "heif",
+            Format::Jxl => "jxl",
+            Format::Gif => "gif",
+            Format::Tiff => "tiff",
+            Format::Svg => "svg",
+            Format::Pdf => "pdf",
+        }
+    }
+
+    // Formats that are converted to variants. Other formats are served as they are. HEIF and
+    // JPEG XL can only be decoded when libvips is built with libheif and libjxl.
+    pub fn is_optimizable(&self) -> bool {
+        matches!(
+            self,
+            Format::Jpeg
+                | Format::Png
+                | Format::Webp
+                | Format::Avif
+                | Format::Heif
+                | Format::Jxl
+                | Format::Gif
+                | Format::Svg
+                | Format::Pdf
+        )
+    }
+
+    // Formats that are rasterized at a density. They aren't served as they are when that fails,
+    // as SVGs can run scripts in browsers.
+    pub fn is_vector(&self) -> bool {
+        matches!(self, Format::Svg | Format::Pdf)
+    }
+}
+
+// Brands of ISO base media files, eg. AVIF and HEIF, are listed in the ftyp box at the start
+fn has_brand(buffer: &[u8], brands: &[&[u8]]) -> bool {
+    if buffer.len() < 12 || &buffer[4..8] != b"ftyp" {
+        return false;
+    }
+
+    let size = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    let end = size.min(buffer.len());
+
+    // The major brand is followed by a version and the compatible brands
+    let major = &buffer[8..12];
+    let compatible = buffer.get(16..end).unwrap_or_default().chunks_exact(4);
+
+    std::iter::once(major)
+        .chain(compatible)
+        .any(|brand| brands.contains(&brand))
+}
+
+// The root element has to be svg, after any XML declaration, comments and doctype, so that
+// eg. HTML pages with inline SVGs aren't mistaken for images
+fn is_svg(buffer: &[u8]) -> bool {
+    let start = &buffer[..buffer.len().min(SVG_SNIFF_LENGTH)];
+    let text = String::from_utf8_lossy(start);
+    let mut text = text.trim_start_matches('\u{feff}').trim_start();
+
+    loop {
+        let end = if text.starts_with("<?") {
+            text.find("?>").map(|index| index + 2)
+        } else if text.starts_with("<!--") {
+            text.find("-->").map(|index| index + 3)
+        } else if text.starts_with("<!") {
+            text.find('>').map(|index| index + 1)
+        } else {
+            return text.starts_with("<svg");
+        };
+
+        match end {
+            Some(end) => text = text[end..].trim_start(),
+            None => return false,
+        }
+    }
+}
+
+pub fn sniff(buffer: &[u8]) -> Option<Format> {
+    if buffer.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(Format::Jpeg)
+    } else if buffer.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(Format::Png)
+    } else if buffer.len() >= 12 && &buffer[0..4] == b"RIFF" && &buffer[8..12] == b"WEBP" {
+        Some(Format::Webp)
+    } else if has_brand(buffer, &[b"avif", b"avis"]) {
+        Some(Format::Avif)
+    } else if has_brand(buffer, &HEIF_BRANDS) {
+        Some(Format::Heif)
+    } else if buffer.starts_with(JXL_CODESTREAM) || buffer.starts_with(JXL_CONTAINER) {
+        Some(Format::Jxl)
+    } else if buffer.starts_with(b"GIF87a") || buffer.starts_with(b"GIF89a") {
+        Some(Format::Gif)
+    } else if buffer.starts_with(b"II*\0") || buffer.starts_with(b"MM\0*") {
+        Some(Format::Tiff)
+    } else if buffer.starts_with(b"%PDF-") {
+        Some(Format::Pdf)
+    } else if is_svg(buffer) {
+        Some(Format::Svg)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::image::fixture;
+
+    #[test]
+    fn sniffs_formats_from_magic_bytes() {
+        assert_eq!(sniff(b"\xFF\xD8\xFF\xE0\0\x10JFIF"), Some(Format::Jpeg));
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some(Format::Png));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some(Format::Webp));
+        assert_eq!(sniff(b"GIF89a\x01\0\x01\0"), Some(Format::Gif));
+        assert_eq!(
+            sniff(b"\0\0\0\x1cftypavif\0\0\0\0avifmif1miaf"),
+            Some(Format::Avif)
+        );
+        assert_eq!(
+            sniff(b"\0\0\0\x1cftypmif1\0\0\0\0mif1avifmiaf"),
+            Some(Format::Avif)
+        );
+        assert_eq!(
+            sniff(b"<?xml version=\"1.0\"?>\n<!-- icon -->\n<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+            Some(Format::Svg)
+        );
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some(Format::Pdf));
+    }
+
+    // The fixtures are the headers of HEIF and JPEG XL files, which is all that is sniffed
+    #[test]
+    fn sniffs_heif_and_jpeg_xl_fixtures() {
+        assert_eq!(sniff(&fixture("header.heic")), Some(Format::Heif));
+        assert_eq!(sniff(&fixture("header.heifs")), Some(Format::Heif));
+        assert_eq!(sniff(&fixture("header.jxl")), Some(Format::Jxl));
+        assert_eq!(sniff(&fixture("container.jxl")), Some(Format::Jxl));
+    }
+
+    #[test]
+    fn rejects_files_that_are_not_images() {
+        assert_eq!(sniff(b"<!DOCTYPE html><html><body></body></html>"), None);
+        assert_eq!(sniff(b"<html><body><svg></svg></body></html>"), None);
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42\0\0\0\0mp42isom"), None);
+        assert_eq!(sniff(b""), None);
+    }
+}
@@ -0,0 +1,347 @@
+use super::format::{self, Format};
+use crate::error::Error;
+use crate::utils::read_env;
+use libvips::VipsImage;
+
+// Shrinking JPEGs while decoding only supports these factors
+const JPEG_SHRINK_FACTORS: [u32; 3] = [2, 4, 8];
+
+// Pages of multi-page images, eg. frames of animations, to load
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pages {
+    First,
+    All,
+    Page(u32),
+}
+
+// Limits on the images that are processed. Images are checked using only their header, so that
+// decompression bombs are rejected before any pixels are decoded.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_bytes: usize,
+    pub max_width: u64,
+    pub max_height: u64,
+    pub max_pixels: u64,
+    pub max_frames: u64,
+}
+
+fn to_option_string(options: &[String]) -> String {
+    if options.is_empty() {
+        String::new()
+    } else {
+        format!("[{}]", options.join(","))
+    }
+}
+
+pub fn decode_failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not decode image: {}", error))
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let max_megapixels: f64 = read_env("IMAGE_MAX_MEGAPIXELS", 50.0);
+
+        Limits {
+            max_bytes: read_env("IMAGE_MAX_BYTES", 50 * 1024 * 1024),
+            max_width: read_env("IMAGE_MAX_WIDTH", 12000),
+            max_height: read_env("IMAGE_MAX_HEIGHT", 12000),
+            max_pixels: (max_megapixels * 1_000_000.0) as u64,
+            max_frames: read_env("IMAGE_MAX_FRAMES", 200),
+        }
+    }
+
+    // How much an image with these dimensions has to shrink to fit the limits. 1 or less when
+    // it already fits. Every frame that is decoded counts towards the pixels.
+    fn shrink_factor(&self, width: u64, height: u64, frames: u64) -> f64 {
+        let pixels = (width * height * frames.max(1)) as f64;
+
+        (width as f64 / self.max_width as f64)
+            .max(height as f64 / self.max_height as f64)
+            .max((pixels / self.max_pixels as f64).sqrt())
+    }
+
+    // Smallest factor that JPEGs can be shrunk by while decoding that makes them fit, or none
+    // when even the largest isn't enough
+    fn jpeg_shrink(factor: f64) -> Option<u32> {
+        JPEG_SHRINK_FACTORS
+            .into_iter()
+            .find(|shrink| *shrink as f64 >= factor)
+    }
+
+    // Largest factor that JPEGs can be shrunk by while decoding without shrinking more than
+    // `factor`, 1 when none can
+    fn jpeg_sample_shrink(factor: f64) -> u32 {
+        JPEG_SHRINK_FACTORS
+            .into_iter()
+            .filter(|shrink| *shrink as f64 <= factor)
+            .max()
+            .unwrap_or(1)
+    }
+
+    fn too_large(&self, width: u64, height: u64, frames: u64) -> Error {
+        let frames = if frames > 1 {
+            format!(" with {} frames", frames)
+        } else {
+            String::new()
+        };
+        Error::TooLarge(format!(
+            "Image is {}x{}{}, which is larger than {}x{} or {} pixels",
+            width, height, frames, self.max_width, self.max_height, self.max_pixels
+        ))
+    }
+
+    // Opens the image after checking its header against the limits. JPEG, WebP, SVG and PDF
+    // images that are too large are shrunk while they are decoded, other formats are rejected.
+    // SVG and PDF images are rasterized at the given DPI. Returns the image along with how much
+    // it was shrunk, 1 when it wasn't.
+    pub fn load(
+        &self,
+        buffer: &[u8],
+        pages: Pages,
+        density: Option<u32>,
+    ) -> Result<(VipsImage, f64), Error> {
+        self.open(buffer, pages, density, None)
+    }
+
+    // Opens the first page of the image to sample its pixels, shrunk while decoding as far as its
+    // longest side stays at least `size`. Formats that can't be shrunk while decoding are
+    // decoded in full.
+    pub fn load_sample(&self, buffer: &[u8], size: u32) -> Result<(VipsImage, f64), Error> {
+        self.open(buffer, Pages::First, None, Some(size))
+    }
+
+    fn open(
+        &self,
+        buffer: &[u8],
+        pages: Pages,
+        density: Option<u32>,
+        sample: Option<u32>,
+    ) -> Result<(VipsImage, f64), Error> {
+        if buffer.len() > self.max_bytes {
+            return Err(Error::TooLarge(format!(
+                "Image is {} bytes, which is larger than {} bytes",
+                buffer.len(),
+                self.max_bytes
+            )));
+        }
+
+        let format = format::sniff(buffer);
+        let mut options = vec![];
+        if let (Some(density), Some(Format::Svg | Format::Pdf)) = (density, format) {
+            options.push(format!("dpi={}", density));
+        }
+        let header_options = options.len();
+
+        // libvips only reads the header until pixels are needed
+        let header = VipsImage::new_from_buffer(buffer, &to_option_string(&options))
+            .map_err(decode_failed)?;
+        let width = header.get_width().max(0) as u64;
+        let height = header.get_height().max(0) as u64;
+        let frames = header.get_n_pages().max(1) as u64;
+
+        // Only a single page of PDFs is rasterized, so long documents are fine
+        if frames > self.max_frames && format != Some(Format::Pdf) {
+            return Err(Error::TooLarge(format!(
+                "Image has {} frames, which is more than {} frames",
+                frames, self.max_frames
+            )));
+        }
+
+        // Frames that are decoded, which all have the size of the first one
+        let decoded = match pages {
+            Pages::All => frames,
+            _ => 1,
+        };
+        match pages {
+            Pages::All if frames > 1 => options.push(String::from("n=-1")),
+            Pages::Page(page) if page as u64 >= frames => {
+                return Err(Error::InvalidParameter(format!(
+                    "Image only has {} pages",
+                    frames
+                )))
+            }
+            Pages::Page(page) if page > 0 => options.push(format!("page={}", page)),
+            _ => {}
+        }
+
+        // How much the image has to shrink, and how much samples can shrink
+        let needed = self.shrink_factor(width, height, decoded);
+        let wanted = sample.map_or(needed, |size| {
+            needed.max(width.max(height) as f64 / size.max(1) as f64)
+        });
+        let factor = match format {
+            Some(Format::Jpeg) if wanted > 1.0 => {
+                let shrink = if needed > 1.0 {
+                    Limits::jpeg_shrink(needed)
+                        .ok_or_else(|| self.too_large(width, height, decoded))?
+                } else {
+                    1
+                }
+                .max(Limits::jpeg_sample_shrink(wanted));
+                if shrink > 1 {
+                    options.push(format!("shrink={}", shrink));
+                }
+                shrink as f64
+            }
+            Some(Format::Webp | Format::Svg | Format::Pdf) if wanted > 1.0 => {
+                options.push(format!("scale={}", 1.0 / wanted));
+                wanted
+            }
+            _ if needed > 1.0 => return Err(self.too_large(width, height, decoded)),
+            _ => 1.0,
+        };
+
+        if options.len() == header_options {
+            return Ok((header, 1.0));
+        }
+
+        let image = VipsImage::new_from_buffer(buffer, &to_option_string(&options))
+            .map_err(decode_failed)?;
+        // Pages of PDFs can each have a different size than the first one, so they are checked
+        // again
+        if factor <= 1.0 && format != Some(Format::Pdf) {
+            return Ok((image, 1.0));
+        }
+
+        // Frames of animated images are stacked vertically, each page height tall
+        let shrunk_width = image.get_width().max(0) as u64;
+        let shrunk_height = image.get_page_height().max(0) as u64;
+
+        // Rounding while shrinking can leave the image a pixel over the limits
+        if self.shrink_factor(shrunk_width, shrunk_height, decoded) > 1.01 {
+            return Err(if factor > 1.0 {
+                self.too_large(width, height, decoded)
+            } else {
+                self.too_large(shrunk_width, shrunk_height, decoded)
+            });
+        }
+        if factor <= 1.0 {
+            return Ok((image, 1.0));
+        }
+
+        if needed > 1.0 {
+            log::info!(
+                "Shrunk {}x{} image to {}x{} while loading",
+                width,
+                height,
+                shrunk_width,
+                shrunk_height
+            );
+        }
+        Ok((image, width as f64 / shrunk_width.max(1) as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> Limits {
+        Limits {
+            max_bytes: 1024,
+            max_width: 4000,
+            max_height: 3000,
+            max_pixels: 6_000_000,
+            max_frames: 10,
+        }
+    }
+
+    #[test]
+    fn computes_shrink_factors() {
+        let limits = limits();
+
+        assert_eq!(limits.shrink_factor(2000, 1500, 1), 0.5);
+        assert_eq!(limits.shrink_factor(4000, 1000, 1), 1.0);
+        // The side that is furthest over its limit decides
+        assert_eq!(limits.shrink_factor(8000, 1000, 1), 2.0);
+        assert_eq!(limits.shrink_factor(1000, 9000, 1), 3.0);
+        // Images within both sides can still have too many pixels, which shrink by the square
+        // root as both sides shrink
+        assert_eq!(limits.shrink_factor(4000, 3000, 1), 2f64.sqrt());
+        assert_eq!(limits.shrink_factor(0, 0, 1), 0.0);
+    }
+
+    #[test]
+    fn counts_the_pixels_of_every_frame() {
+        let limits = limits();
+
+        assert!(limits.shrink_factor(1000, 1000, 1) < 1.0);
+        assert_eq!(limits.shrink_factor(1000, 1000, 6), 1.0);
+        assert_eq!(limits.shrink_factor(1000, 1000, 24), 2.0);
+        // Frames don't change how far over its sides the image is
+        assert_eq!(limits.shrink_factor(8000, 100, 2), 2.0);
+    }
+
+    #[test]
+    fn limits_the_pixels_of_animations() {
+        // 4x4 GIF with 3 frames, so 48 pixels in all
+        let gif = crate::services::image::fixture("frames.gif");
+        let limits = Limits {
+            max_pixels: 40,
+            ..limits()
+        };
+
+        let (image, _) = limits.load(&gif, Pages::First, None).unwrap();
+        assert_eq!((image.get_width(), image.get_height()), (4, 4));
+        // GIFs can't be shrunk while they are decoded
+        assert!(matches!(
+            limits.load(&gif, Pages::All, None),
+            Err(Error::TooLarge(_))
+        ));
+
+        let limits = Limits {
+            max_pixels: 48,
+            ..limits
+        };
+        let (image, _) = limits.load(&gif, Pages::All, None).unwrap();
+        assert_eq!((image.get_width(), image.get_height()), (4, 12));
+    }
+
+    #[test]
+    fn picks_the_smallest_jpeg_shrink_that_fits() {
+        assert_eq!(Limits::jpeg_shrink(1.01), Some(2));
+        assert_eq!(Limits::jpeg_shrink(2.0), Some(2));
+        assert_eq!(Limits::jpeg_shrink(2.5), Some(4));
+        assert_eq!(Limits::jpeg_shrink(7.9), Some(8));
+        assert_eq!(Limits::jpeg_shrink(8.0), Some(8));
+        assert_eq!(Limits::jpeg_shrink(8.1), None);
+
+        let limits = limits();
+        assert_eq!(
+            Limits::jpeg_shrink(limits.shrink_factor(20000, 15000, 1)),
+            Some(8)
+        );
+        assert_eq!(
+            Limits::jpeg_shrink(limits.shrink_factor(36000, 1000, 1)),
+            None
+        );
+    }
+
+    #[test]
+    fn shrinks_jpeg_samples_no_smaller_than_asked() {
+        assert_eq!(Limits::jpeg_sample_shrink(1.5), 1);
+        assert_eq!(Limits::jpeg_sample_shrink(2.0), 2);
+        assert_eq!(Limits::jpeg_sample_shrink(7.9), 4);
+        assert_eq!(Limits::jpeg_sample_shrink(100.0), 8);
+    }
+
+    #[test]
+    fn shrinks_samples_while_decoding() {
+        let pixels = vec![128; 1000 * 800 * 3];
+        let image =
+            VipsImage::new_from_memory(&pixels, 1000, 800, 3, libvips::ops::BandFormat::Uchar)
+                .unwrap();
+        let jpeg = libvips::ops::jpegsave_buffer(&image).unwrap();
+        let limits = Limits {
+            max_bytes: usize::MAX,
+            ..limits()
+        };
+
+        let (sample, shrink) = limits.load_sample(&jpeg, 64).unwrap();
+        assert_eq!((sample.get_width(), sample.get_height()), (125, 100));
+        assert_eq!(shrink, 8.0);
+        // The full image fits the limits, so it isn't shrunk otherwise
+        let (image, shrink) = limits.load(&jpeg, Pages::First, None).unwrap();
+        assert_eq!((image.get_width(), shrink), (1000, 1.0));
+    }
+}
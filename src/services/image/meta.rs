@@ -0,0 +1,90 @@
+use super::exif;
+use super::format::Format;
+use super::limits::{self, Limits};
+use super::palette::{self, Colors};
+use super::pipeline;
+use super::svg;
+use crate::error::Error;
+use libvips::ops::Interpretation;
+use libvips::VipsImage;
+use serde::Serialize;
+
+// Facts about the source image that clients would otherwise download it to learn
+#[derive(Debug, Serialize)]
+pub struct Meta {
+    // Size of the upright image, after the EXIF orientation is applied
+    pub width: u32,
+    pub height: u32,
+    pub format: &'static str,
+    pub color_space: &'static str,
+    pub has_alpha: bool,
+    pub frames: u32,
+    pub orientation: u8,
+    pub bytes: usize,
+    // Empty when the image is too large to decode
+    #[serde(flatten)]
+    pub colors: Colors,
+}
+
+fn color_space(interpretation: Interpretation) -> &'static str {
+    match interpretation {
+        Interpretation::BW => "b-w",
+        Interpretation::Grey16 => "grey16",
+        Interpretation::Srgb => "srgb",
+        Interpretation::Rgb => "rgb",
+        Interpretation::Rgb16 => "rgb16",
+        Interpretation::Scrgb => "scrgb",
+        Interpretation::Cmyk => "cmyk",
+        Interpretation::Lab | Interpretation::Labq | Interpretation::Labs => "lab",
+        _ => "multiband",
+    }
+}
+
+// Reads the header of the image. Pixels are only decoded, at a small size, for the colors.
+pub fn render(buffer: &[u8], format: Format, limits: &Limits) -> Result<Meta, Error> {
+    let sanitized;
+    let header_buffer = match format {
+        Format::Svg => {
+            sanitized = svg::sanitize(buffer)?;
+            &sanitized
+        }
+        _ => buffer,
+    };
+    let header = VipsImage::new_from_buffer(header_buffer, "").map_err(limits::decode_failed)?;
+
+    let orientation = exif::find(buffer, format)
+        .and_then(exif::orientation)
+        .unwrap_or(1);
+    let (width, height) = (
+        header.get_width().max(0) as u32,
+        header.get_height().max(0) as u32,
+    );
+    let (width, height) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    // Colors are sampled from a copy that is shrunk while decoding where the format allows it
+    let colors =
+        match pipeline::load_upright(buffer, format, limits, Some(palette::SAMPLE_SIZE as u32)) {
+            Ok((image, _)) => palette::colors(&image, palette::DEFAULT_PALETTE_SIZE)?,
+            Err(Error::TooLarge(_)) => Colors::default(),
+            Err(error) => return Err(error),
+        };
+
+    Ok(Meta {
+        width,
+        height,
+        format: format.name(),
+        color_space: header
+            .get_interpretation()
+            .map(color_space)
+            .unwrap_or("multiband"),
+        has_alpha: header.image_hasalpha(),
+        frames: header.get_n_pages().max(1) as u32,
+        orientation,
+        bytes: buffer.len(),
+        colors,
+    })
+}
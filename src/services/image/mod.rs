@@ -1,18 +1,67 @@
+pub mod adjust;
+pub mod crop;
+pub mod exif;
+pub mod format;
+pub mod limits;
+pub mod meta;
+pub mod overlay;
+pub mod palette;
+pub mod pipeline;
+pub mod placeholder;
+pub mod pool;
+pub mod shape;
+pub mod signature;
+pub mod srcset;
+pub mod svg;
+pub mod text;
+pub mod transform;
+pub mod watermark;
+
 use crate::utils;
 
 use super::storage::{Storage, UploadData};
+use crate::error::Error;
 use anyhow;
 use enum_map::{enum_map, Enum, EnumMap};
-use libvips::ops::webpsave_buffer_with_opts;
-use libvips::VipsImage;
-use libvips::{self, ops};
+use format::Format;
+use limits::Limits;
+use pool::{ImagePool, PoolError};
 use rocket::http::ContentType;
+use serde::{Deserialize, Serialize};
+use transform::{Metadata, Transform};
+use watermark::Watermark;
+
+// Outputs of transforms requested through query parameters are cached under this folder, in a
+// folder for each source image
+const TRANSFORM_PATH: &str = "image_optimizer/transformed";
+
+// Placeholders are cached as JSON under this folder, at the path of the source image
+const PLACEHOLDER_PATH: &str = "image_optimizer/placeholder";
+
+// Metadata is cached as JSON under this folder, at the path of the source image
+const META_PATH: &str = "image_optimizer/meta";
 
-#[derive(Enum)]
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Variants {
     Default,
 }
 
+// Contents of a file in `tests/fixtures`
+#[cfg(test)]
+pub(crate) fn fixture(name: &str) -> Vec<u8> {
+    std::fs::read(format!(
+        "{}/tests/fixtures/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    ))
+    .unwrap()
+}
+
+pub fn all_variants() -> Vec<Variants> {
+    (0..Variants::LENGTH).map(Variants::from_usize).collect()
+}
+
 pub fn get_variant_path(variant: Variants) -> String {
     let folder_name = "image_optimizer";
     let paths: EnumMap<Variants, &str> = enum_map! {
@@ -21,56 +70,244 @@ pub fn get_variant_path(variant: Variants) -> String {
     format!("{}/{}", folder_name, paths[variant].to_string())
 }
 
-pub fn optimize(buffer: &Vec<u8>) -> libvips::Result<Vec<u8>> {
-    let source = VipsImage::new_from_buffer(buffer, "").expect("Error during VipsImage init");
-    let options = ops::WebpsaveBufferOptions {
-        q: 50,
-        strip: true,
-        reduction_effort: 2,
-        ..ops::WebpsaveBufferOptions::default()
-    };
-    webpsave_buffer_with_opts(&source, &options)
+pub fn get_variant_key(key: &str, variant: Variants) -> String {
+    let file_name_without_ext = utils::get_path_without_ext(key);
+    format!(
+        "{}/{}.webp",
+        get_variant_path(variant),
+        file_name_without_ext
+    )
 }
 
-pub async fn generate(key: &str, storage: &Storage) -> anyhow::Result<()> {
-    let file_name_without_ext = utils::get_path_without_ext(key);
-    let variant_path = get_variant_path(Variants::Default);
+// Variants can keep different metadata than the rest of the outputs, set with eg.
+// `IMAGE_METADATA_DEFAULT`
+pub fn get_variant_transform(variant: Variants) -> Transform {
+    let metadata = Metadata::from_env(&format!(
+        "IMAGE_METADATA_{}",
+        format!("{:?}", variant).to_uppercase()
+    ));
+
+    match variant {
+        Variants::Default => Transform {
+            metadata,
+            ..Transform::default()
+        },
+    }
+}
 
-    let target_path = format!("{}/{}.webp", variant_path, file_name_without_ext);
-    let cached_image = storage.read_from_cache(&target_path).await;
+// The variant that a transform produces. Requests without parameters get the default variant.
+pub fn get_transform_variant(transform: &Transform) -> Option<Variants> {
+    if transform.is_default() {
+        return Some(Variants::Default);
+    }
+
+    all_variants()
+        .into_iter()
+        .find(|variant| get_variant_transform(*variant) == *transform)
+}
+
+fn get_transform_folder(key: &str) -> String {
+    format!("{}/{}/", TRANSFORM_PATH, utils::get_path_without_ext(key))
+}
+
+// Transforms that produce a variant share its key
+pub fn get_transform_key(key: &str, transform: &Transform) -> String {
+    if let Some(variant) = get_transform_variant(transform) {
+        return get_variant_key(key, variant);
+    }
+
+    format!(
+        "{}{}.{}",
+        get_transform_folder(key),
+        transform.cache_id(),
+        transform.format.extension()
+    )
+}
+
+async fn render_to_cache(
+    key: &str,
+    transform: &Transform,
+    target_key: &str,
+    force: bool,
+    storage: &Storage,
+    pool: &ImagePool,
+) -> anyhow::Result<()> {
+    if !force && storage.read_from_cache(target_key).await.is_ok() {
+        log::info!("Variant found for {}. Skipping generate flow.", key);
+        return Ok(());
+    }
+
+    let image = storage.read(key, pool.limits().max_bytes).await?;
+    let format = match format::sniff(&image) {
+        Some(format) if format.is_optimizable() => format,
+        Some(format) => {
+            log::info!(
+                "Skipping {}. {:?} images are served as they are",
+                key,
+                format
+            );
+            return Ok(());
+        }
+        None => return Err(Error::UnsupportedFormat(format!("{} is not an image", key)).into()),
+    };
+
+    let watermark = Watermark::load(transform, &pool.watermarks(), storage).await?;
+    let limits = pool.limits();
+    let metadata = pool.metadata();
+    let owned_transform = transform.clone();
+    let result = pool
+        .run(move || {
+            pipeline::render(
+                &image,
+                format,
+                &owned_transform,
+                &limits,
+                metadata,
+                watermark.as_ref(),
+            )
+        })
+        .await?;
+
+    match result {
+        Ok(body) => {
+            storage
+                .write(
+                    target_key,
+                    UploadData {
+                        content_type: transform.format.content_type(),
+                        body,
+                    },
+                )
+                .await?;
 
-    match cached_image {
-        Ok(_) => {
-            log::info!("Variant found for {}. Skipping generate flow.", key);
             Ok(())
         }
-        Err(_error) => {
-            let image = storage.read(key).await?;
-            let result: Result<Vec<u8>, libvips::error::Error> = optimize(&image);
-
-            match result {
-                Ok(optimised_image) => {
-                    let file_name_without_ext = utils::get_path_without_ext(key);
-                    let variant_path = get_variant_path(Variants::Default);
-                    let target_key = format!("{}/{}.webp", variant_path, file_name_without_ext);
-
-                    storage
-                        .write(
-                            &target_key,
-                            UploadData {
-                                content_type: ContentType::WEBP,
-                                body: optimised_image,
-                            },
-                        )
-                        .await?;
-
-                    Ok(())
-                }
-                Err(error) => Err(anyhow::anyhow!(format!(
-                    "Error during optimization. Key: {}, Error: {:?}",
-                    key, error
-                ))),
-            }
+        Err(error) => {
+            Err(anyhow::Error::new(error)
+                .context(format!("Error during optimization. Key: {}", key)))
         }
     }
 }
+
+pub async fn generate(
+    key: &str,
+    variant: Variants,
+    force: bool,
+    storage: &Storage,
+    pool: &ImagePool,
+) -> anyhow::Result<()> {
+    let transform = get_variant_transform(variant);
+    let target_key = get_variant_key(key, variant);
+    render_to_cache(key, &transform, &target_key, force, storage, pool).await
+}
+
+pub async fn generate_transform(
+    key: &str,
+    transform: &Transform,
+    force: bool,
+    storage: &Storage,
+    pool: &ImagePool,
+) -> anyhow::Result<()> {
+    let target_key = get_transform_key(key, transform);
+    render_to_cache(key, transform, &target_key, force, storage, pool).await
+}
+
+fn get_placeholder_key(key: &str) -> String {
+    format!(
+        "{}/{}.json",
+        PLACEHOLDER_PATH,
+        utils::get_path_without_ext(key)
+    )
+}
+
+fn get_meta_key(key: &str) -> String {
+    format!("{}/{}.json", META_PATH, utils::get_path_without_ext(key))
+}
+
+// JSON rendered from the source image on the first request and cached at the target key after
+// that. It is rendered again on the next request when it can't be cached.
+async fn get_json<T, F>(
+    key: &str,
+    target_key: &str,
+    name: &str,
+    storage: &Storage,
+    pool: &ImagePool,
+    render: F,
+) -> Result<Vec<u8>, Error>
+where
+    T: Serialize + Send + 'static,
+    F: FnOnce(&[u8], Format, &Limits) -> Result<T, Error> + Send + 'static,
+{
+    if let Ok(body) = storage.read_from_cache(target_key).await {
+        return Ok(body);
+    }
+
+    let image = storage.read(key, pool.limits().max_bytes).await?;
+    let format = format::sniff(&image)
+        .ok_or_else(|| Error::UnsupportedFormat(format!("{} is not an image", key)))?;
+    let limits = pool.limits();
+    let value = pool
+        .try_run(move || render(&image, format, &limits))
+        .await
+        .map_err(|error| match error {
+            PoolError::Saturated => Error::Overloaded(pool.retry_after()),
+            error => Error::Internal(error.to_string()),
+        })??;
+    let body = serde_json::to_vec(&value)
+        .map_err(|error| Error::Internal(format!("Could not serialize {}: {}", name, error)))?;
+
+    if let Err(error) = storage
+        .write(
+            target_key,
+            UploadData {
+                content_type: ContentType::JSON,
+                body: body.clone(),
+            },
+        )
+        .await
+    {
+        log::warn!("Could not cache {} for {}. {}", name, key, error);
+    }
+
+    Ok(body)
+}
+
+// JSON with the placeholders of the source image
+pub async fn get_placeholder(
+    key: &str,
+    storage: &Storage,
+    pool: &ImagePool,
+) -> Result<Vec<u8>, Error> {
+    let target_key = get_placeholder_key(key);
+    get_json(
+        key,
+        &target_key,
+        "placeholder",
+        storage,
+        pool,
+        placeholder::render,
+    )
+    .await
+}
+
+// JSON with the size, format and other metadata of the source image
+pub async fn get_meta(key: &str, storage: &Storage, pool: &ImagePool) -> Result<Vec<u8>, Error> {
+    let target_key = get_meta_key(key);
+    get_json(key, &target_key, "metadata", storage, pool, meta::render).await
+}
+
+// Removes the given variants along with every cached transform, the placeholder and the metadata
+// of the source image
+pub async fn purge(key: &str, variants: &[Variants], storage: &Storage) -> Result<(), Error> {
+    for variant in variants {
+        storage.delete(&get_variant_key(key, *variant)).await?;
+    }
+    storage.delete(&get_placeholder_key(key)).await?;
+    storage.delete(&get_meta_key(key)).await?;
+
+    for transform_key in storage.list_cache(&get_transform_folder(key)).await? {
+        storage.delete(&transform_key).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+use super::transform::Position;
+use crate::error::Error;
+use libvips::ops::{self, BlendMode, Interpretation};
+use libvips::VipsImage;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not composite overlay: {}", error))
+}
+
+// Composites an overlay with an alpha channel over the image, at the position with the margin
+// between it and the edges of the image
+pub fn composite(
+    image: VipsImage,
+    overlay: &VipsImage,
+    position: Position,
+    margin: u32,
+) -> Result<VipsImage, Error> {
+    let margin = margin as i32;
+    let free_width = image.get_width() - overlay.get_width();
+    let free_height = image.get_height() - overlay.get_height();
+    let x = match position {
+        Position::W | Position::Nw | Position::Sw => margin,
+        Position::Center | Position::N | Position::S => free_width / 2,
+        Position::E | Position::Ne | Position::Se => free_width - margin,
+    };
+    let y = match position {
+        Position::N | Position::Ne | Position::Nw => margin,
+        Position::Center | Position::E | Position::W => free_height / 2,
+        Position::S | Position::Se | Position::Sw => free_height - margin,
+    };
+
+    let format = image.get_format().map_err(failed)?;
+    let has_alpha = image.image_hasalpha();
+    let composited = ops::composite_2_with_opts(
+        &image,
+        overlay,
+        BlendMode::Over,
+        &ops::Composite2Options {
+            x,
+            y,
+            compositing_space: Interpretation::Srgb,
+            premultiplied: false,
+        },
+    )
+    .and_then(|composited| ops::cast(&composited, format))
+    .map_err(failed)?;
+
+    // Compositing adds an alpha channel, which images without one don't need
+    if has_alpha || !composited.image_hasalpha() {
+        return Ok(composited);
+    }
+    let bands = composited.get_bands();
+    ops::extract_band_with_opts(&composited, 0, &ops::ExtractBandOptions { n: bands - 1 })
+        .map_err(failed)
+}
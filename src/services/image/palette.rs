@@ -0,0 +1,239 @@
+use super::pipeline;
+use super::transform::Color;
+use crate::error::Error;
+use libvips::VipsImage;
+use serde::Serialize;
+use std::cmp::Reverse;
+
+// Longest side of the image that colors are sampled from
+pub const SAMPLE_SIZE: i32 = 64;
+
+// Colors in palettes that don't set their size, eg. the one returned with metadata
+pub const DEFAULT_PALETTE_SIZE: u32 = 5;
+
+// Pixels that are mostly transparent don't count towards the colors of the image
+const MIN_ALPHA: u8 = 128;
+
+// Colors of an image as hex strings, eg. `#3a6ea5`. The dominant color is missing when the
+// image is transparent.
+#[derive(Debug, Default, Serialize)]
+pub struct Colors {
+    pub dominant_color: Option<String>,
+    pub palette: Vec<String>,
+}
+
+// Pixels that share the top 4 bits of every channel, so that noise doesn't split similar colors
+#[derive(Clone, Copy, Debug, Default)]
+struct Bucket {
+    key: [u8; 3],
+    count: u64,
+    sum: [u64; 3],
+}
+
+impl Bucket {
+    fn color(&self) -> Color {
+        let average = |channel: usize| ((self.sum[channel] + self.count / 2) / self.count) as u8;
+        Color {
+            red: average(0),
+            green: average(1),
+            blue: average(2),
+            alpha: 255,
+        }
+    }
+
+    fn merge(buckets: &[Bucket]) -> Bucket {
+        buckets
+            .iter()
+            .fold(Bucket::default(), |mut merged, bucket| {
+                merged.count += bucket.count;
+                for (sum, value) in merged.sum.iter_mut().zip(bucket.sum) {
+                    *sum += value;
+                }
+                merged
+            })
+    }
+}
+
+// Buckets of the RGBA pixels, from the most to the least common. Ties keep the order of the
+// buckets, so that the colors of an image are always the same.
+fn buckets(rgba: &[u8]) -> Vec<Bucket> {
+    let mut buckets = vec![Bucket::default(); 16 * 16 * 16];
+    for pixel in rgba.chunks_exact(4).filter(|pixel| pixel[3] >= MIN_ALPHA) {
+        let key = [pixel[0] >> 4, pixel[1] >> 4, pixel[2] >> 4];
+        let bucket =
+            &mut buckets[(key[0] as usize) << 8 | (key[1] as usize) << 4 | key[2] as usize];
+        bucket.key = key;
+        bucket.count += 1;
+        for (sum, value) in bucket.sum.iter_mut().zip(pixel) {
+            *sum += *value as u64;
+        }
+    }
+
+    let mut buckets: Vec<Bucket> = buckets
+        .into_iter()
+        .filter(|bucket| bucket.count > 0)
+        .collect();
+    buckets.sort_by_key(|bucket| Reverse(bucket.count));
+    buckets
+}
+
+// Most common color of RGBA pixels, or none when they are all transparent
+pub fn dominant(rgba: &[u8]) -> Option<Color> {
+    buckets(rgba).first().map(Bucket::color)
+}
+
+// Sum of the squared distances of the keys of the buckets from their mean along each channel,
+// weighted by the pixels of the buckets
+fn errors(buckets: &[Bucket]) -> [f64; 3] {
+    let count = Bucket::merge(buckets).count as f64;
+    let mut errors = [0.0; 3];
+    for (channel, error) in errors.iter_mut().enumerate() {
+        let (sum, squares) = buckets.iter().fold((0.0, 0.0), |(sum, squares), bucket| {
+            let key = bucket.key[channel] as f64;
+            (
+                sum + bucket.count as f64 * key,
+                squares + bucket.count as f64 * key * key,
+            )
+        });
+        *error = squares - sum * sum / count;
+    }
+    errors
+}
+
+// Index that splits buckets sorted along the channel into the two groups with the least error
+// between them, so that small areas of distinct colors are split from large ones instead of
+// being averaged into them. Each side gets at least a bucket.
+fn split_index(buckets: &[Bucket], channel: usize) -> usize {
+    let (mut count, mut sum, mut squares) = (0.0, 0.0, 0.0);
+    let prefixes: Vec<(f64, f64, f64)> = buckets
+        .iter()
+        .map(|bucket| {
+            let key = bucket.key[channel] as f64;
+            count += bucket.count as f64;
+            sum += bucket.count as f64 * key;
+            squares += bucket.count as f64 * key * key;
+            (count, sum, squares)
+        })
+        .collect();
+    let error = |(count, sum, squares): (f64, f64, f64)| squares - sum * sum / count;
+
+    (1..buckets.len())
+        .map(|index| {
+            let lower = prefixes[index - 1];
+            let upper = (count - lower.0, sum - lower.1, squares - lower.2);
+            (index, error(lower) + error(upper))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map_or(1, |(index, _)| index)
+}
+
+// Up to `size` colors of RGBA pixels, from the most to the least common. Buckets are grouped by
+// a variance-based median cut, so that the palette spans the colors of the image instead of
+// shades of the most common one. The first color isn't always the dominant one, which is a
+// single bucket.
+pub fn palette(rgba: &[u8], size: u32) -> Vec<Color> {
+    let mut boxes = vec![buckets(rgba)];
+    boxes.retain(|buckets| !buckets.is_empty());
+
+    while boxes.len() < size as usize {
+        // The box whose colors are the furthest from its average is split, so that large areas
+        // of similar colors aren't split before areas of distinct ones
+        let index = (0..boxes.len())
+            .filter(|index| boxes[*index].len() > 1)
+            .map(|index| (index, errors(&boxes[index]).iter().sum::<f64>()))
+            .fold(
+                None,
+                |best: Option<(usize, f64)>, (index, error)| match best {
+                    Some((_, best_error)) if best_error >= error => best,
+                    _ => Some((index, error)),
+                },
+            );
+        let Some((index, _)) = index else {
+            break;
+        };
+
+        // Along the channel with the most error
+        let mut buckets = boxes.remove(index);
+        let errors = errors(&buckets);
+        let channel = (0..3).fold(0, |best, channel| {
+            if errors[channel] > errors[best] {
+                channel
+            } else {
+                best
+            }
+        });
+        buckets.sort_by_key(|bucket| bucket.key[channel]);
+
+        let upper = buckets.split_off(split_index(&buckets, channel));
+        boxes.insert(index, upper);
+        boxes.insert(index, buckets);
+    }
+
+    let mut colors: Vec<Bucket> = boxes.iter().map(|buckets| Bucket::merge(buckets)).collect();
+    colors.sort_by_key(|bucket| Reverse(bucket.count));
+    colors.iter().map(Bucket::color).collect()
+}
+
+// Dominant color and palette of `size` colors of the image, which are sampled from a small copy
+pub fn colors(image: &VipsImage, size: u32) -> Result<Colors, Error> {
+    let pixels = pipeline::thumbnail(image, SAMPLE_SIZE)?.image_write_to_memory();
+    let hex = |color: Color| format!("#{}", color.name());
+
+    Ok(Colors {
+        dominant_color: dominant(&pixels).map(hex),
+        palette: palette(&pixels, size).into_iter().map(hex).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The fixtures are binary PPMs, so that their pixels can be read without a decoder
+    fn fixture(name: &str) -> Vec<u8> {
+        let data = crate::services::image::fixture(name);
+        let pixels = data.splitn(4, |byte| *byte == b'\n').nth(3).unwrap();
+        pixels
+            .chunks_exact(3)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2], 255])
+            .collect()
+    }
+
+    fn names(colors: Vec<Color>) -> Vec<String> {
+        colors.iter().map(Color::name).collect()
+    }
+
+    #[test]
+    fn finds_dominant_colors() {
+        let mut pixels = [255, 0, 0, 255].repeat(3);
+        pixels.extend([0, 0, 255, 255].repeat(2));
+        pixels.extend([0, 255, 0, 0].repeat(10));
+        // Similar colors are averaged
+        pixels.extend([250, 2, 0, 255]);
+
+        let color = dominant(&pixels).unwrap();
+        assert_eq!((color.red, color.green, color.blue), (254, 1, 0));
+        assert_eq!(dominant(&[0, 0, 0, 0]), None);
+        assert_eq!(dominant(&fixture("flag.ppm")).unwrap().name(), "e61e28");
+    }
+
+    #[test]
+    fn extracts_palettes() {
+        let flag = fixture("flag.ppm");
+        assert_eq!(names(palette(&flag, 3)), ["e61e28", "fafafa", "143cc8"]);
+        // Palettes don't have more colors than the image
+        assert_eq!(palette(&flag, 8).len(), 3);
+        assert_eq!(names(palette(&flag, 1)), ["ca6c89"]);
+
+        // The small sun gets its own color before shades of the grass and the sky
+        let landscape = fixture("landscape.ppm");
+        assert_eq!(
+            names(palette(&landscape, 3)),
+            ["37803b", "64a5df", "ffd23c"]
+        );
+        let colors = names(palette(&landscape, 5));
+        assert_eq!(colors, ["3b803b", "69acdf", "5c99df", "2b803b", "ffd23c"]);
+        assert_eq!(colors, names(palette(&landscape, 5)));
+        assert!(palette(&[0, 0, 0, 0], 4).is_empty());
+    }
+}
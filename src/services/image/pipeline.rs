@@ -0,0 +1,540 @@
+use super::adjust;
+use super::crop;
+use super::exif;
+use super::format::{self, Format};
+use super::limits::{Limits, Pages};
+use super::overlay;
+use super::palette::{self, DEFAULT_PALETTE_SIZE};
+use super::shape;
+use super::svg;
+use super::text;
+use super::transform::{Color, Fit, Flip, Metadata, OutputFormat, Transform};
+use super::watermark::Watermark;
+use crate::error::Error;
+use libvips::ops::{self, Angle, BandFormat, CompassDirection, Direction, Extend, Interpretation};
+use libvips::VipsImage;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not process image: {}", error))
+}
+
+fn svg_only() -> Error {
+    Error::InvalidParameter(String::from("Only SVG images can be served as SVG"))
+}
+
+// Splits animations, which libvips loads as frames stacked on top of each other, into frames
+fn split_frames(image: VipsImage) -> Result<Vec<VipsImage>, Error> {
+    let width = image.get_width();
+    let page_height = image.get_page_height();
+    let frames = image.get_height() / page_height.max(1);
+    if frames <= 1 {
+        return Ok(vec![image]);
+    }
+
+    (0..frames)
+        .map(|frame| ops::extract_area(&image, 0, frame * page_height, width, page_height))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(failed)
+}
+
+// Stacks frames back into a single image, returning it along with the height of a frame
+fn join_frames(mut frames: Vec<VipsImage>) -> Result<(VipsImage, i32), Error> {
+    if frames.len() == 1 {
+        let image = frames.remove(0);
+        let height = image.get_height();
+        return Ok((image, height));
+    }
+
+    let page_height = frames[0].get_height();
+    let image = ops::arrayjoin_with_opts(
+        &mut frames,
+        &ops::ArrayjoinOptions {
+            across: 1,
+            background: vec![0.0],
+            ..ops::ArrayjoinOptions::default()
+        },
+    )
+    .map_err(failed)?;
+
+    Ok((image, page_height))
+}
+
+// Rotates the image upright using its EXIF orientation, which is lost when metadata is stripped,
+// and crops it to the requested rectangle, which is relative to the upright image
+fn upright(image: VipsImage, transform: &Transform, shrink: f64) -> Result<VipsImage, Error> {
+    let image = ops::autorot(&image).map_err(failed)?;
+
+    match transform.rect {
+        Some(rect) => crop::rect(image, rect, shrink),
+        None => Ok(image),
+    }
+}
+
+fn orient(image: VipsImage, transform: &Transform) -> Result<VipsImage, Error> {
+    let image = match transform.rotation {
+        90 => ops::rot(&image, Angle::D90).map_err(failed)?,
+        180 => ops::rot(&image, Angle::D180).map_err(failed)?,
+        270 => ops::rot(&image, Angle::D270).map_err(failed)?,
+        _ => image,
+    };
+
+    match transform.flip {
+        Some(Flip::Horizontal) => ops::flip(&image, Direction::Horizontal).map_err(failed),
+        Some(Flip::Vertical) => ops::flip(&image, Direction::Vertical).map_err(failed),
+        None => Ok(image),
+    }
+}
+
+// Scales the image, premultiplying the alpha channel so that transparent pixels don't bleed
+// into the edges
+pub(super) fn scale(image: VipsImage, horizontal: f64, vertical: f64) -> Result<VipsImage, Error> {
+    if horizontal == 1.0 && vertical == 1.0 {
+        return Ok(image);
+    }
+
+    let options = ops::ResizeOptions {
+        vscale: vertical,
+        ..ops::ResizeOptions::default()
+    };
+
+    if !image.image_hasalpha() {
+        return ops::resize_with_opts(&image, horizontal, &options).map_err(failed);
+    }
+
+    let format = image.get_format().map_err(failed)?;
+    let premultiplied = ops::premultiply(&image).map_err(failed)?;
+    let resized = ops::resize_with_opts(&premultiplied, horizontal, &options).map_err(failed)?;
+    let unpremultiplied = ops::unpremultiply(&resized).map_err(failed)?;
+    ops::cast(&unpremultiplied, format).map_err(failed)
+}
+
+fn resize(image: VipsImage, transform: &Transform) -> Result<VipsImage, Error> {
+    let width = image.get_width() as f64;
+    let height = image.get_height() as f64;
+
+    let (horizontal, vertical) = match (transform.width, transform.height, transform.fit) {
+        (None, None, _) => return Ok(image),
+        // Images are only enlarged when they have to fill the box
+        (Some(target), None, _) => {
+            let factor = (target as f64 / width).min(1.0);
+            (factor, factor)
+        }
+        (None, Some(target), _) => {
+            let factor = (target as f64 / height).min(1.0);
+            (factor, factor)
+        }
+        (Some(target_width), Some(target_height), Fit::Fill) => {
+            (target_width as f64 / width, target_height as f64 / height)
+        }
+        (Some(target_width), Some(target_height), Fit::Cover) => {
+            let factor = (target_width as f64 / width).max(target_height as f64 / height);
+            (factor, factor)
+        }
+        (Some(target_width), Some(target_height), Fit::Inside | Fit::Contain) => {
+            let factor = (target_width as f64 / width)
+                .min(target_height as f64 / height)
+                .min(1.0);
+            (factor, factor)
+        }
+    };
+
+    let image = scale(image, horizontal, vertical)?;
+
+    match (transform.width, transform.height, transform.fit) {
+        (Some(target_width), Some(target_height), Fit::Cover) => {
+            let width = image.get_width().min(target_width as i32);
+            let height = image.get_height().min(target_height as i32);
+            ops::extract_area(
+                &image,
+                (image.get_width() - width) / 2,
+                (image.get_height() - height) / 2,
+                width,
+                height,
+            )
+            .map_err(failed)
+        }
+        (Some(target_width), Some(target_height), Fit::Contain) => {
+            // The padding is the background, or transparent without one
+            let (image, background) = shape::background(image, transform.background)?;
+
+            ops::gravity_with_opts(
+                &image,
+                CompassDirection::Centre,
+                target_width as i32,
+                target_height as i32,
+                &ops::GravityOptions {
+                    extend: Extend::Background,
+                    background,
+                },
+            )
+            .map_err(failed)
+        }
+        _ => Ok(image),
+    }
+}
+
+// Converts the image to sRGB using its ICC profile, so that wide gamut images keep their colors
+// once the profile is stripped. Images without a profile are assumed to be sRGB, or CMYK.
+pub(super) fn to_srgb(image: VipsImage) -> Result<VipsImage, Error> {
+    let input_profile = match image.get_interpretation() {
+        Ok(Interpretation::Cmyk) => "cmyk",
+        _ => "srgb",
+    };
+
+    let options = ops::IccTransformOptions {
+        embedded: true,
+        input_profile: input_profile.to_string(),
+        ..ops::IccTransformOptions::default()
+    };
+    match ops::icc_transform_with_opts(&image, "srgb", &options) {
+        Ok(converted) => Ok(converted),
+        // Greyscale images without a profile don't match the fallback profile
+        Err(_) => ops::colourspace(&image, Interpretation::Srgb).map_err(failed),
+    }
+}
+
+// The first page of the source image in sRGB, turned upright, along with how much it was shrunk
+// while loading. Samples are shrunk while loading as far as their longest side stays at least
+// the sample size.
+pub(super) fn load_upright(
+    buffer: &[u8],
+    format: Format,
+    limits: &Limits,
+    sample: Option<u32>,
+) -> Result<(VipsImage, f64), Error> {
+    let sanitized;
+    let buffer = match format {
+        Format::Svg => {
+            sanitized = svg::sanitize(buffer)?;
+            &sanitized
+        }
+        _ => buffer,
+    };
+
+    let (image, shrink) = match sample {
+        Some(size) => limits.load_sample(buffer, size)?,
+        None => limits.load(buffer, Pages::First, None)?,
+    };
+    let image = ops::autorot(&to_srgb(image)?).map_err(failed)?;
+    Ok((image, shrink))
+}
+
+// 8 bit sRGB pixels with an alpha channel, shrunk so that the longest side is at most `size`
+pub(super) fn thumbnail(image: &VipsImage, size: i32) -> Result<VipsImage, Error> {
+    let factor = (size as f64 / image.get_width().max(image.get_height()) as f64).min(1.0);
+    let image = scale(ops::copy(image).map_err(failed)?, factor, factor)?;
+    let image = ops::colourspace(&image, Interpretation::Srgb)
+        .and_then(|image| ops::cast(&image, BandFormat::Uchar))
+        .map_err(failed)?;
+    shape::with_alpha(image)
+}
+
+fn encode(
+    image: &VipsImage,
+    page_height: i32,
+    format: OutputFormat,
+    metadata: Metadata,
+    background: Option<Color>,
+) -> Result<Vec<u8>, Error> {
+    // Savers either keep all metadata or none of it, so it is always stripped and the sRGB
+    // profile is embedded by the savers that support one. AVIF and JPEG XL mark their colors as
+    // sRGB without a profile.
+    let profile = match metadata {
+        Metadata::Strip => "none",
+        Metadata::Copyright | Metadata::Icc => "srgb",
+    }
+    .to_string();
+
+    let result = match format {
+        OutputFormat::Webp => ops::webpsave_buffer_with_opts(
+            image,
+            &ops::WebpsaveBufferOptions {
+                q: 50,
+                strip: true,
+                profile,
+                reduction_effort: 2,
+                page_height,
+                ..ops::WebpsaveBufferOptions::default()
+            },
+        ),
+        OutputFormat::Avif => ops::heifsave_buffer_with_opts(
+            image,
+            &ops::HeifsaveBufferOptions {
+                q: 50,
+                compression: ops::ForeignHeifCompression::Av1,
+                strip: true,
+                ..ops::HeifsaveBufferOptions::default()
+            },
+        ),
+        OutputFormat::Jpeg => {
+            // JPEG has no alpha channel, so transparent pixels are flattened onto the
+            // background, or white
+            let flattened;
+            let image = if image.image_hasalpha() {
+                let background = background.map_or(vec![255.0], |color| {
+                    vec![color.red as f64, color.green as f64, color.blue as f64]
+                });
+                flattened = ops::flatten_with_opts(
+                    image,
+                    &ops::FlattenOptions {
+                        background,
+                        ..ops::FlattenOptions::default()
+                    },
+                )
+                .and_then(|image| ops::cast(&image, BandFormat::Uchar))
+                .map_err(failed)?;
+                &flattened
+            } else {
+                image
+            };
+
+            ops::jpegsave_buffer_with_opts(
+                image,
+                &ops::JpegsaveBufferOptions {
+                    q: 80,
+                    optimize_coding: true,
+                    strip: true,
+                    profile,
+                    ..ops::JpegsaveBufferOptions::default()
+                },
+            )
+        }
+        OutputFormat::Png => ops::pngsave_buffer_with_opts(
+            image,
+            &ops::PngsaveBufferOptions {
+                bitdepth: 8,
+                strip: true,
+                profile,
+                ..ops::PngsaveBufferOptions::default()
+            },
+        ),
+        // The bindings don't include the JPEG XL saver, so it is picked by its suffix
+        OutputFormat::Jxl => image.image_write_to_buffer(".jxl[Q=75,strip]"),
+        OutputFormat::Svg => return Err(svg_only()),
+        OutputFormat::Json => {
+            return Err(Error::Internal(String::from(
+                "Colors are extracted instead of encoding the image",
+            )))
+        }
+    };
+
+    result.map_err(|error| Error::DecodeFailed(format!("Could not encode image: {}", error)))
+}
+
+// Applies the transform to the source image and encodes the result. Frames of animated images
+// are transformed one at a time and kept when the output format supports animation.
+pub fn render(
+    buffer: &[u8],
+    format: Format,
+    transform: &Transform,
+    limits: &Limits,
+    metadata: Metadata,
+    watermark: Option<&Watermark>,
+) -> Result<Vec<u8>, Error> {
+    // SVGs are sanitized before librsvg sees them, so that it doesn't load anything else
+    let sanitized;
+    let buffer = match format {
+        Format::Svg => {
+            sanitized = svg::sanitize(buffer)?;
+            if transform.format == OutputFormat::Svg {
+                return Ok(sanitized);
+            }
+            &sanitized
+        }
+        _ if transform.format == OutputFormat::Svg => return Err(svg_only()),
+        _ => buffer,
+    };
+
+    let pages = match (format, transform.frame) {
+        (Format::Pdf, _) => Pages::Page(transform.page.unwrap_or(1) - 1),
+        (_, Some(frame)) => Pages::Page(frame),
+        _ if transform.format.supports_animation() => Pages::All,
+        _ => Pages::First,
+    };
+
+    let (image, shrink) = limits.load(buffer, pages, transform.density)?;
+    let mut frames = split_frames(to_srgb(image)?)?
+        .into_iter()
+        .map(|frame| upright(frame, transform, shrink))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(threshold) = transform.trim {
+        frames = crop::trim(frames, threshold)?;
+    }
+    let mut frames = frames
+        .into_iter()
+        .map(|frame| adjust::apply(resize(orient(frame, transform)?, transform)?, transform))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Every frame has the same size, so the watermark is only prepared once
+    let sanitized_watermark;
+    if let Some(watermark) = watermark {
+        let buffer = match format::sniff(&watermark.image) {
+            Some(Format::Svg) => {
+                sanitized_watermark = svg::sanitize(&watermark.image)?;
+                &sanitized_watermark
+            }
+            _ => &watermark.image,
+        };
+        let (overlay, _) = limits.load(buffer, Pages::First, None)?;
+        let overlay = to_srgb(overlay)?;
+        let (width, height) = (frames[0].get_width(), frames[0].get_height());
+        if let Some(overlay) = watermark.prepare(overlay, width, height)? {
+            frames = frames
+                .into_iter()
+                .map(|frame| {
+                    overlay::composite(frame, &overlay, watermark.position, watermark.margin)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+        }
+    }
+
+    if let Some(caption) = text::render(transform, &frames[0])? {
+        let (position, margin) = (text::position(transform), text::margin(transform));
+        frames = frames
+            .into_iter()
+            .map(|frame| overlay::composite(frame, &caption, position, margin))
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+
+    let frames = frames
+        .into_iter()
+        .map(|frame| shape::apply(frame, transform))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Only the first frame is rendered, as JSON doesn't support animation
+    if transform.format == OutputFormat::Json {
+        let colors = palette::colors(
+            &frames[0],
+            transform.palette.unwrap_or(DEFAULT_PALETTE_SIZE),
+        )?;
+        return serde_json::to_vec(&colors)
+            .map_err(|error| Error::Internal(format!("Could not serialize colors: {}", error)));
+    }
+
+    let (image, page_height) = join_frames(frames)?;
+    let metadata = transform.metadata.unwrap_or(metadata);
+    let encoded = encode(
+        &image,
+        page_height,
+        transform.format,
+        metadata,
+        transform.background,
+    )?;
+
+    // Only the artist and copyright are copied from the source, as the rest can eg. hold where
+    // the image was taken
+    match exif::copyright(buffer, format) {
+        Some(tiff) if metadata == Metadata::Copyright => {
+            Ok(exif::insert(encoded, transform.format, &tiff))
+        }
+        _ => Ok(encoded),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // EXIF data with a description, an artist and a copyright, all in line or after the IFD
+    fn source_exif() -> Vec<u8> {
+        let fields: [(u16, &[u8]); 3] = [
+            (0x010E, b"Taken at home\0"),
+            (0x013B, b"Jane Doe\0"),
+            (0x8298, b"(c) Jane Doe\0"),
+        ];
+        let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+        tiff.extend(3u16.to_le_bytes());
+        let mut offset = 8 + 2 + 3 * 12 + 4;
+        let mut values: Vec<u8> = vec![];
+        for (tag, value) in fields {
+            tiff.extend(tag.to_le_bytes());
+            tiff.extend(2u16.to_le_bytes());
+            tiff.extend((value.len() as u32).to_le_bytes());
+            tiff.extend((offset as u32).to_le_bytes());
+            values.extend(value);
+            offset += value.len();
+        }
+        tiff.extend([0; 4]);
+        tiff.extend(values);
+        tiff
+    }
+
+    fn source() -> Vec<u8> {
+        let pixels: Vec<u8> = (0..32 * 24 * 3).map(|index| (index % 251) as u8).collect();
+        let image = VipsImage::new_from_memory(&pixels, 32, 24, 3, BandFormat::Uchar).unwrap();
+        let jpeg = ops::jpegsave_buffer(&image).unwrap();
+        exif::insert(jpeg, OutputFormat::Jpeg, &source_exif())
+    }
+
+    fn has_profile(image: &[u8]) -> bool {
+        [b"ICC_PROFILE".as_slice(), b"iCCP", b"ICCP"]
+            .iter()
+            .any(|marker| image.windows(marker.len()).any(|window| window == *marker))
+    }
+
+    #[test]
+    fn keeps_metadata_of_the_policy() {
+        let source = source();
+        let copyright = exif::copyright(&source, Format::Jpeg).unwrap();
+
+        for (fm, format) in [
+            ("jpeg", Format::Jpeg),
+            ("png", Format::Png),
+            ("webp", Format::Webp),
+        ] {
+            let output = |metadata: &str| {
+                let transform =
+                    Transform::from_query(&format!("fm={}&meta={}", fm, metadata)).unwrap();
+                render(
+                    &source,
+                    Format::Jpeg,
+                    &transform,
+                    &Limits::from_env(),
+                    Metadata::Strip,
+                    None,
+                )
+                .unwrap()
+            };
+
+            let stripped = output("strip");
+            assert_eq!(exif::find(&stripped, format), None, "{}", fm);
+            assert!(!has_profile(&stripped), "{}", fm);
+
+            let icc = output("icc");
+            assert_eq!(exif::find(&icc, format), None, "{}", fm);
+            assert!(has_profile(&icc), "{}", fm);
+
+            // The description is left out along with everything else
+            let copied = output("copyright");
+            assert_eq!(
+                exif::find(&copied, format),
+                Some(copyright.as_slice()),
+                "{}",
+                fm
+            );
+            assert!(has_profile(&copied), "{}", fm);
+        }
+    }
+
+    #[test]
+    fn rotates_before_flipping() {
+        // 2x1 image of a black and a white pixel
+        let image = || VipsImage::new_from_memory(&[0, 255], 2, 1, 1, BandFormat::Uchar).unwrap();
+        let orient = |query: &str| {
+            let oriented = orient(image(), &Transform::from_query(query).unwrap()).unwrap();
+            (
+                oriented.get_width(),
+                oriented.get_height(),
+                oriented.image_write_to_memory(),
+            )
+        };
+
+        assert_eq!(orient("rot=0"), (2, 1, vec![0, 255]));
+        assert_eq!(orient("rot=90"), (1, 2, vec![0, 255]));
+        assert_eq!(orient("rot=180"), (2, 1, vec![255, 0]));
+        assert_eq!(orient("rot=270"), (1, 2, vec![255, 0]));
+        assert_eq!(orient("flip=h"), (2, 1, vec![255, 0]));
+        assert_eq!(orient("flip=v"), (2, 1, vec![0, 255]));
+        assert_eq!(orient("rot=90&flip=v"), (1, 2, vec![255, 0]));
+    }
+}
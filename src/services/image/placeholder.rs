@@ -0,0 +1,317 @@
+use super::format::Format;
+use super::limits::Limits;
+use super::pipeline;
+use crate::error::Error;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use libvips::ops;
+use serde::Serialize;
+use std::f64::consts::PI;
+
+// Longest side of the image that the hashes are computed from. ThumbHash allows up to 100.
+const HASH_SIZE: i32 = 32;
+
+// Longest side of the inline image
+const LQIP_SIZE: i32 = 16;
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not render placeholder: {}", error))
+}
+
+// Placeholders shown while the image loads, along with the size of the upright source image
+#[derive(Debug, Serialize)]
+pub struct Placeholder {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    // Base64 encoded
+    pub thumbhash: String,
+    // Data URI of a tiny WebP
+    pub lqip: String,
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let value = value as f64 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.0031308 {
+        (value * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn push_base83(hash: &mut String, value: u32, length: u32) {
+    for digit in (0..length).rev() {
+        let index = (value / 83u32.pow(digit)) % 83;
+        hash.push(BASE83[index as usize] as char);
+    }
+}
+
+// BlurHash of RGBA pixels, with 4 components along the longest side and 3 along the other, as
+// in https://github.com/woltapp/blurhash. Alpha is ignored.
+fn blurhash(width: usize, height: usize, rgba: &[u8]) -> String {
+    let (components_x, components_y) = if width >= height { (4, 3) } else { (3, 4) };
+    let mut factors = Vec::with_capacity(components_x * components_y);
+
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for y in 0..height {
+                for x in 0..width {
+                    let basis = normalisation
+                        * (PI * i as f64 * x as f64 / width as f64).cos()
+                        * (PI * j as f64 * y as f64 / height as f64).cos();
+                    let pixel = &rgba[4 * (x + y * width)..];
+                    for (channel, value) in factor.iter_mut().enumerate() {
+                        *value += basis * srgb_to_linear(pixel[channel]);
+                    }
+                }
+            }
+            factors.push(factor.map(|value| value / (width * height) as f64));
+        }
+    }
+
+    let mut hash = String::new();
+    push_base83(
+        &mut hash,
+        ((components_x - 1) + (components_y - 1) * 9) as u32,
+        1,
+    );
+
+    let dc = factors[0];
+    let ac = &factors[1..];
+    let actual_max = ac
+        .iter()
+        .flat_map(|factor| factor.iter())
+        .fold(0.0f64, |max, value| max.max(value.abs()));
+    let quantised_max = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+    let max = (quantised_max + 1) as f64 / 166.0;
+    push_base83(&mut hash, quantised_max, 1);
+
+    let dc = (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]);
+    push_base83(&mut hash, dc, 4);
+
+    for factor in ac {
+        let quantise = |value: f64| {
+            let value = value / max;
+            (value.signum() * value.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        };
+        let value = quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]);
+        push_base83(&mut hash, value, 2);
+    }
+
+    hash
+}
+
+// DCT of a channel, returning its constant term, its varying terms normalized to 0 to 1 and the
+// scale they were normalized by
+fn encode_channel(
+    channel: &[f64],
+    width: usize,
+    height: usize,
+    nx: usize,
+    ny: usize,
+) -> (f64, Vec<f64>, f64) {
+    let (mut dc, mut ac, mut scale) = (0.0, vec![], 0.0f64);
+
+    for cy in 0..ny {
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            let mut factor = 0.0;
+            for y in 0..height {
+                let fy = (PI / height as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                for x in 0..width {
+                    let fx = (PI / width as f64 * cx as f64 * (x as f64 + 0.5)).cos();
+                    factor += channel[x + y * width] * fx * fy;
+                }
+            }
+            factor /= (width * height) as f64;
+
+            if cx > 0 || cy > 0 {
+                ac.push(factor);
+                scale = scale.max(factor.abs());
+            } else {
+                dc = factor;
+            }
+            cx += 1;
+        }
+    }
+
+    if scale > 0.0 {
+        for factor in ac.iter_mut() {
+            *factor = 0.5 + 0.5 / scale * *factor;
+        }
+    }
+
+    (dc, ac, scale)
+}
+
+// ThumbHash of RGBA pixels, as in https://github.com/evanw/thumbhash. Both sides have to be 100
+// pixels or less.
+fn thumbhash(width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+    let pixels = width * height;
+
+    // Average color, weighted by alpha
+    let (mut average_r, mut average_g, mut average_b, mut average_a) = (0.0, 0.0, 0.0, 0.0);
+    for pixel in rgba.chunks_exact(4) {
+        let alpha = pixel[3] as f64 / 255.0;
+        average_r += alpha / 255.0 * pixel[0] as f64;
+        average_g += alpha / 255.0 * pixel[1] as f64;
+        average_b += alpha / 255.0 * pixel[2] as f64;
+        average_a += alpha;
+    }
+    if average_a > 0.0 {
+        average_r /= average_a;
+        average_g /= average_a;
+        average_b /= average_a;
+    }
+
+    // Fewer luminance components are kept when there is alpha
+    let has_alpha = average_a < pixels as f64;
+    let limit = if has_alpha { 5.0 } else { 7.0 };
+    let longest = width.max(height) as f64;
+    let lx = ((limit * width as f64 / longest).round() as usize).max(1);
+    let ly = ((limit * height as f64 / longest).round() as usize).max(1);
+
+    // Luminance, yellow-blue, red-green and alpha, composited over the average color
+    let (mut l, mut p, mut q, mut a) = (
+        Vec::with_capacity(pixels),
+        Vec::with_capacity(pixels),
+        Vec::with_capacity(pixels),
+        Vec::with_capacity(pixels),
+    );
+    for pixel in rgba.chunks_exact(4) {
+        let alpha = pixel[3] as f64 / 255.0;
+        let r = average_r * (1.0 - alpha) + alpha / 255.0 * pixel[0] as f64;
+        let g = average_g * (1.0 - alpha) + alpha / 255.0 * pixel[1] as f64;
+        let b = average_b * (1.0 - alpha) + alpha / 255.0 * pixel[2] as f64;
+        l.push((r + g + b) / 3.0);
+        p.push((r + g) / 2.0 - b);
+        q.push(r - g);
+        a.push(alpha);
+    }
+
+    let (l_dc, l_ac, l_scale) = encode_channel(&l, width, height, lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = encode_channel(&p, width, height, 3, 3);
+    let (q_dc, q_ac, q_scale) = encode_channel(&q, width, height, 3, 3);
+
+    let is_landscape = width > height;
+    let header24 = (63.0 * l_dc).round() as u32
+        | ((31.5 + 31.5 * p_dc).round() as u32) << 6
+        | ((31.5 + 31.5 * q_dc).round() as u32) << 12
+        | ((31.0 * l_scale).round() as u32) << 18
+        | (has_alpha as u32) << 23;
+    let header16 = (if is_landscape { ly } else { lx }) as u32
+        | ((63.0 * p_scale).round() as u32) << 3
+        | ((63.0 * q_scale).round() as u32) << 9
+        | (is_landscape as u32) << 15;
+    let mut hash = vec![
+        (header24 & 255) as u8,
+        ((header24 >> 8) & 255) as u8,
+        (header24 >> 16) as u8,
+        (header16 & 255) as u8,
+        (header16 >> 8) as u8,
+    ];
+
+    let mut channels = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = encode_channel(&a, width, height, 5, 5);
+        hash.push((15.0 * a_dc).round() as u8 | ((15.0 * a_scale).round() as u8) << 4);
+        channels.push(a_ac);
+    }
+
+    // Varying terms are packed two to a byte
+    let start = hash.len();
+    for (index, factor) in channels.iter().flatten().enumerate() {
+        if start + index / 2 == hash.len() {
+            hash.push(0);
+        }
+        hash[start + index / 2] |= ((15.0 * factor).round() as u8) << ((index & 1) * 4);
+    }
+
+    hash
+}
+
+pub fn render(buffer: &[u8], format: Format, limits: &Limits) -> Result<Placeholder, Error> {
+    let (image, shrunk) = pipeline::load_upright(buffer, format, limits, None)?;
+
+    let small = pipeline::thumbnail(&image, HASH_SIZE)?;
+    let (width, height) = (small.get_width() as usize, small.get_height() as usize);
+    let pixels = small.image_write_to_memory();
+
+    let lqip = ops::webpsave_buffer_with_opts(
+        &pipeline::thumbnail(&image, LQIP_SIZE)?,
+        &ops::WebpsaveBufferOptions {
+            q: 20,
+            strip: true,
+            ..ops::WebpsaveBufferOptions::default()
+        },
+    )
+    .map_err(failed)?;
+
+    Ok(Placeholder {
+        width: (image.get_width() as f64 * shrunk).round() as u32,
+        height: (image.get_height() as f64 * shrunk).round() as u32,
+        blurhash: blurhash(width, height, &pixels),
+        thumbhash: STANDARD.encode(thumbhash(width, height, &pixels)),
+        lqip: format!("data:image/webp;base64,{}", STANDARD.encode(lqip)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(width: usize, height: usize, pixel: [u8; 4]) -> Vec<u8> {
+        pixel.repeat(width * height)
+    }
+
+    #[test]
+    fn hashes_solid_images() {
+        // 4x3 components and a white constant term
+        let hash = blurhash(8, 6, &solid(8, 6, [255, 255, 255, 255]));
+        assert_eq!(&hash[..1], "L");
+        assert_eq!(&hash[2..6], "TSUA");
+        assert_eq!(hash.len(), 28);
+        assert_eq!(&blurhash(6, 8, &solid(6, 8, [0, 0, 0, 255]))[..1], "T");
+
+        let white = solid(8, 8, [255, 255, 255, 255]);
+        let hash = thumbhash(8, 8, &white);
+        assert_eq!(&hash[..5], &[63, 8, 2, 7, 0]);
+        // 27 luminance and 5 of each color term, two to a byte
+        assert_eq!(hash.len(), 5 + 19);
+    }
+
+    #[test]
+    fn hashes_images_deterministically() {
+        let pixels: Vec<u8> = (0..12 * 9)
+            .flat_map(|index| [(index * 7) as u8, (index * 3) as u8, 200, 255 - index as u8])
+            .collect();
+
+        assert_eq!(blurhash(12, 9, &pixels), blurhash(12, 9, &pixels));
+        assert_eq!(blurhash(12, 9, &pixels).len(), 28);
+        assert_ne!(
+            blurhash(12, 9, &pixels),
+            blurhash(12, 9, &solid(12, 9, [255; 4]))
+        );
+
+        let hash = thumbhash(12, 9, &pixels);
+        assert_eq!(hash, thumbhash(12, 9, &pixels));
+        // Alpha is flagged in the header
+        assert_eq!(hash[2] >> 7, 1);
+    }
+}
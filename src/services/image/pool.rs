@@ -0,0 +1,163 @@
+use super::limits::Limits;
+use super::transform::Metadata;
+use super::watermark::{self, Preset};
+use crate::utils::read_env;
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use rocket::tokio::task::{self, JoinError};
+use rocket::tokio::time;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum PoolError {
+    // All workers stayed busy for longer than the queue timeout
+    Saturated,
+    Panicked(JoinError),
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::Saturated => write!(f, "Image processing pool is saturated"),
+            PoolError::Panicked(error) => write!(f, "Image processing panicked: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+// Runs libvips work on tokio's blocking threads, so that encoding doesn't block the async
+// runtime, with at most `size` jobs running at the same time. Clones share the same limit.
+#[derive(Clone)]
+pub struct ImagePool {
+    _permits: Arc<Semaphore>,
+    _queue_timeout: Duration,
+    _limits: Limits,
+    _metadata: Metadata,
+    _watermarks: Arc<HashMap<String, Preset>>,
+}
+
+// The permit is held until the work is done, however long it takes
+async fn spawn<F, T>(permit: OwnedSemaphorePermit, work: F) -> Result<T, PoolError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(move || {
+        let result = work();
+        drop(permit);
+        result
+    })
+    .await
+    .map_err(PoolError::Panicked)
+}
+
+impl ImagePool {
+    // Waits for as long as it takes for a worker to become free
+    pub async fn run<F, T>(&self, work: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = self
+            ._permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| PoolError::Saturated)?;
+
+        spawn(permit, work).await
+    }
+
+    // Gives up with PoolError::Saturated when no worker becomes free within the queue timeout.
+    // Once the work has started, it runs to completion.
+    pub async fn try_run<F, T>(&self, work: F) -> Result<T, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = time::timeout(self._queue_timeout, self._permits.clone().acquire_owned())
+            .await
+            .map_err(|_| PoolError::Saturated)?
+            .map_err(|_| PoolError::Saturated)?;
+
+        spawn(permit, work).await
+    }
+
+    // Limits on the images that jobs on this pool accept
+    pub fn limits(&self) -> Limits {
+        self._limits
+    }
+
+    // Metadata kept in outputs that don't set a policy of their own
+    pub fn metadata(&self) -> Metadata {
+        self._metadata
+    }
+
+    // Watermark presets that transforms can apply by name
+    pub fn watermarks(&self) -> Arc<HashMap<String, Preset>> {
+        self._watermarks.clone()
+    }
+
+    // Seconds after which clients should retry requests that were rejected as saturated
+    pub fn retry_after(&self) -> u64 {
+        self._queue_timeout.as_secs().max(1)
+    }
+}
+
+pub fn initialize() -> ImagePool {
+    let size = read_env(
+        "IMAGE_POOL_SIZE",
+        thread::available_parallelism().map_or(1, |size| size.get()),
+    )
+    .max(1);
+    let queue_timeout: u64 = read_env("IMAGE_POOL_QUEUE_TIMEOUT", 5000);
+
+    ImagePool {
+        _permits: Arc::new(Semaphore::new(size)),
+        _queue_timeout: Duration::from_millis(queue_timeout),
+        _limits: Limits::from_env(),
+        _metadata: Metadata::from_env("IMAGE_METADATA").unwrap_or_default(),
+        _watermarks: Arc::new(watermark::presets_from_env()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(size: usize, queue_timeout: u64) -> ImagePool {
+        ImagePool {
+            _permits: Arc::new(Semaphore::new(size)),
+            _queue_timeout: Duration::from_millis(queue_timeout),
+            _limits: Limits::from_env(),
+            _metadata: Metadata::default(),
+            _watermarks: Arc::new(HashMap::new()),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn times_out_waiting_for_a_worker_only() {
+        let pool = pool(1, 50);
+
+        // Work that takes longer than the queue timeout still completes
+        let slow = || {
+            thread::sleep(Duration::from_millis(200));
+            "done"
+        };
+        assert_eq!(pool.try_run(slow).await.unwrap(), "done");
+
+        let busy = pool.clone();
+        let running = rocket::tokio::spawn(async move { busy.run(slow).await });
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(matches!(
+            pool.try_run(|| "queued").await,
+            Err(PoolError::Saturated)
+        ));
+        assert_eq!(running.await.unwrap().unwrap(), "done");
+        assert_eq!(pool.try_run(|| "free").await.unwrap(), "free");
+    }
+}
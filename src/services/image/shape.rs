@@ -0,0 +1,207 @@
+use super::overlay;
+use super::transform::{Color, Mask, Position, Transform};
+use crate::error::Error;
+use libvips::ops::{self, BandFormat, Extend};
+use libvips::VipsImage;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not shape image: {}", error))
+}
+
+pub fn with_alpha(image: VipsImage) -> Result<VipsImage, Error> {
+    if image.image_hasalpha() {
+        return Ok(image);
+    }
+
+    let max = match image.get_format().map_err(failed)? {
+        BandFormat::Ushort => 65535.0,
+        _ => 255.0,
+    };
+    ops::bandjoin_const(&image, &mut [max]).map_err(failed)
+}
+
+// The image along with the background that it is extended with. Transparent backgrounds, and
+// the default one, add an alpha channel to images without one.
+pub fn background(image: VipsImage, color: Option<Color>) -> Result<(VipsImage, Vec<f64>), Error> {
+    match color {
+        Some(color) if color.is_opaque() && !image.image_hasalpha() => Ok((
+            image,
+            vec![color.red as f64, color.green as f64, color.blue as f64],
+        )),
+        Some(color) => Ok((
+            with_alpha(image)?,
+            vec![
+                color.red as f64,
+                color.green as f64,
+                color.blue as f64,
+                color.alpha as f64,
+            ],
+        )),
+        None => Ok((with_alpha(image)?, vec![0.0])),
+    }
+}
+
+// Adds `size` pixels of the color, or transparent pixels, on every side of the image
+fn extend(image: VipsImage, size: u32, color: Option<Color>) -> Result<VipsImage, Error> {
+    let (image, background) = background(image, color)?;
+    let size = size as i32;
+
+    ops::embed_with_opts(
+        &image,
+        size,
+        size,
+        image.get_width() + 2 * size,
+        image.get_height() + 2 * size,
+        &ops::EmbedOptions {
+            extend: Extend::Background,
+            background,
+        },
+    )
+    .map_err(failed)
+}
+
+// Coverage of every pixel of a width by height image by a rounded rectangle of the given size and
+// corner radius in its center, from 0 to 255. Edges are antialiased over a pixel.
+fn coverage(width: i32, height: i32, shape: (f64, f64), radius: f64) -> Vec<u8> {
+    let (half_width, half_height) = (shape.0 / 2.0, shape.1 / 2.0);
+    let radius = radius.min(half_width).min(half_height).max(0.0);
+    let mut mask = Vec::with_capacity((width.max(0) * height.max(0)) as usize);
+
+    for y in 0..height {
+        for x in 0..width {
+            // Signed distance from the edge of the shape, which is negative inside of it
+            let qx = (x as f64 + 0.5 - width as f64 / 2.0).abs() - (half_width - radius);
+            let qy = (y as f64 + 0.5 - height as f64 / 2.0).abs() - (half_height - radius);
+            let distance = qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - radius;
+            mask.push(((0.5 - distance).clamp(0.0, 1.0) * 255.0).round() as u8);
+        }
+    }
+
+    mask
+}
+
+// Size and corner radius of the shape that a width by height image is cut to, grown by `grow`
+// on every side
+fn outline(transform: &Transform, width: i32, height: i32, grow: f64) -> ((f64, f64), f64) {
+    match transform.mask {
+        Some(Mask::Circle) => {
+            let diameter = width.min(height) as f64 + 2.0 * grow;
+            ((diameter, diameter), diameter / 2.0)
+        }
+        None => (
+            (width as f64 + 2.0 * grow, height as f64 + 2.0 * grow),
+            transform.radius.unwrap_or(0) as f64 + grow,
+        ),
+    }
+}
+
+// libvips doesn't copy the pixels it is given, so the mask is copied before it is dropped
+fn to_image(mask: Vec<u8>, width: i32, height: i32) -> Result<VipsImage, Error> {
+    VipsImage::new_from_memory(&mask, width, height, 1, BandFormat::Uchar)
+        .and_then(VipsImage::image_copy_memory)
+        .map_err(failed)
+}
+
+// Multiplies the alpha channel of the image by the mask
+fn cut(image: VipsImage, mask: &VipsImage) -> Result<VipsImage, Error> {
+    let image = with_alpha(image)?;
+    let format = image.get_format().map_err(failed)?;
+    let bands = image.get_bands();
+
+    let colour = ops::extract_band_with_opts(&image, 0, &ops::ExtractBandOptions { n: bands - 1 })
+        .map_err(failed)?;
+    let alpha = ops::extract_band(&image, bands - 1).map_err(failed)?;
+    let mask = ops::cast(mask, BandFormat::Float).map_err(failed)?;
+    let scale = VipsImage::new_from_image(&mask, &[1.0 / 255.0]).map_err(failed)?;
+    let alpha = ops::multiply(&mask, &scale)
+        .and_then(|mask| ops::multiply(&alpha, &mask))
+        .and_then(|alpha| ops::cast(&alpha, format))
+        .map_err(failed)?;
+
+    ops::bandjoin(&mut [colour, alpha]).map_err(failed)
+}
+
+// Adds the border, cuts the image to its rounded corners or circle and pads it. Borders follow
+// the shape of the image.
+pub fn apply(image: VipsImage, transform: &Transform) -> Result<VipsImage, Error> {
+    let is_shaped = transform.radius.is_some() || transform.mask.is_some();
+    let mut image = image;
+
+    match (transform.border, is_shaped) {
+        (Some(border), false) => image = extend(image, border.width, Some(border.color))?,
+        (border, true) => {
+            // Circles are cut from the square in the center of the image
+            if transform.mask == Some(Mask::Circle) {
+                let size = image.get_width().min(image.get_height());
+                image = ops::extract_area(
+                    &image,
+                    (image.get_width() - size) / 2,
+                    (image.get_height() - size) / 2,
+                    size,
+                    size,
+                )
+                .map_err(failed)?;
+            }
+
+            let (width, height) = (image.get_width(), image.get_height());
+            let inner = outline(transform, width, height, 0.0);
+            let mask = coverage(width, height, inner.0, inner.1);
+            image = cut(image, &to_image(mask, width, height)?)?;
+
+            if let Some(border) = border {
+                // The border fills the space between the shape and the shape grown by its width
+                let outer = outline(transform, width, height, border.width as f64);
+                image = extend(image, border.width, None)?;
+                let (width, height) = (image.get_width(), image.get_height());
+                let ring = coverage(width, height, outer.0, outer.1)
+                    .into_iter()
+                    .zip(coverage(width, height, inner.0, inner.1))
+                    .map(|(outer, inner)| {
+                        let coverage = outer.saturating_sub(inner) as u32;
+                        (coverage * border.color.alpha as u32 / 255) as u8
+                    })
+                    .collect();
+                let ring = to_image(ring, width, height)?;
+
+                let color = border.color;
+                let fill = VipsImage::new_from_image(
+                    &image,
+                    &[color.red as f64, color.green as f64, color.blue as f64],
+                )
+                .and_then(|fill| ops::cast(&fill, BandFormat::Uchar))
+                .and_then(|fill| ops::bandjoin(&mut [fill, ring]))
+                .map_err(failed)?;
+                image = overlay::composite(image, &fill, Position::Nw, 0)?;
+            }
+        }
+        (None, false) => {}
+    }
+
+    match transform.padding {
+        Some(padding) => extend(image, padding, transform.background),
+        None => Ok(image),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn covers_rounded_rectangles() {
+        // Square corners cover the whole image
+        assert!(coverage(4, 3, (4.0, 3.0), 0.0).iter().all(|c| *c == 255));
+
+        let circle = coverage(10, 10, (10.0, 10.0), 5.0);
+        assert_eq!(circle[0], 0);
+        assert_eq!(circle[5 * 10 + 5], 255);
+        assert_eq!(circle[5 * 10 + 1], 255);
+        // Edges are antialiased
+        assert!(circle.iter().any(|c| *c > 0 && *c < 255));
+
+        // Circles are centered in the image
+        let circle = coverage(20, 10, (10.0, 10.0), 5.0);
+        assert_eq!(circle[5 * 20 + 2], 0);
+        assert_eq!(circle[5 * 20 + 10], 255);
+    }
+}
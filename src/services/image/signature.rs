@@ -0,0 +1,112 @@
+use super::transform::Transform;
+use crate::error::Error;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Signs transforms, so that only URLs handed out by huffman, eg. by the srcset endpoint, are
+// rendered. Every transform is rendered when `URL_SIGNING_KEY` isn't set.
+#[derive(Clone, Default)]
+pub struct Signer {
+    _key: Option<Vec<u8>>,
+}
+
+impl Signer {
+    pub fn new(key: &[u8]) -> Self {
+        Signer {
+            _key: Some(key.to_vec()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        env::var("URL_SIGNING_KEY")
+            .ok()
+            .filter(|key| !key.is_empty())
+            .map_or_else(Signer::default, |key| Signer::new(key.as_bytes()))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self._key.is_some()
+    }
+
+    // The signature covers the key of the image and the canonical query of the transform, so
+    // that the order of parameters doesn't matter
+    fn mac(&self, key: &str, transform: &Transform) -> Option<HmacSha256> {
+        let mut mac = HmacSha256::new_from_slice(self._key.as_ref()?).ok()?;
+        mac.update(key.as_bytes());
+        mac.update(b"?");
+        mac.update(transform.to_query().as_bytes());
+        Some(mac)
+    }
+
+    // Value of the `s` parameter for the transform, or none when signing isn't enabled
+    pub fn sign(&self, key: &str, transform: &Transform) -> Option<String> {
+        self.mac(key, transform)
+            .map(|mac| URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes()))
+    }
+
+    // Requests without parameters get the default variant, so they don't need a signature
+    pub fn verify(
+        &self,
+        key: &str,
+        transform: &Transform,
+        signature: Option<&str>,
+    ) -> Result<(), Error> {
+        let mac = match self.mac(key, transform) {
+            Some(mac) if !transform.is_default() => mac,
+            _ => return Ok(()),
+        };
+
+        let signature = signature
+            .and_then(|signature| URL_SAFE_NO_PAD.decode(signature).ok())
+            .ok_or_else(|| Error::Unauthorized(format!("Missing signature for {}", key)))?;
+        mac.verify_slice(&signature)
+            .map_err(|_| Error::Unauthorized(format!("Invalid signature for {}", key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_signed_transforms() {
+        let signer = Signer::new(b"secret");
+        let transform = Transform::from_query("w=300&fm=avif").unwrap();
+        let signature = signer.sign("photos/a.jpg", &transform).unwrap();
+
+        assert!(signer
+            .verify("photos/a.jpg", &transform, Some(&signature))
+            .is_ok());
+        // Parameters in another order have the same signature
+        let reordered = Transform::from_query("fm=avif&w=300").unwrap();
+        assert!(signer
+            .verify("photos/a.jpg", &reordered, Some(&signature))
+            .is_ok());
+
+        let wider = Transform::from_query("w=600&fm=avif").unwrap();
+        assert!(signer
+            .verify("photos/a.jpg", &wider, Some(&signature))
+            .is_err());
+        assert!(signer
+            .verify("photos/b.jpg", &transform, Some(&signature))
+            .is_err());
+        assert!(signer.verify("photos/a.jpg", &transform, None).is_err());
+        assert!(signer
+            .verify("photos/a.jpg", &transform, Some("%%"))
+            .is_err());
+        assert!(signer
+            .verify("photos/a.jpg", &Transform::default(), None)
+            .is_ok());
+
+        // Nothing is signed or checked without a key
+        assert_eq!(Signer::default().sign("photos/a.jpg", &transform), None);
+        assert!(Signer::default()
+            .verify("photos/a.jpg", &transform, None)
+            .is_ok());
+    }
+}
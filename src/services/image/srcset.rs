@@ -0,0 +1,468 @@
+use super::signature::Signer;
+use super::transform::{OutputFormat, Transform, MAX_DIMENSION};
+use crate::error::Error;
+use rocket::http::RawStr;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+// Most widths that a srcset can have
+pub const MAX_WIDTHS: usize = 20;
+
+// Images fill the width of the viewport unless `sizes` says otherwise
+const DEFAULT_SIZES: &str = "100vw";
+
+// Parameters of srcset requests. The rest are transform parameters applied at every width.
+const PARAMS: [&str; 5] = ["widths", "breakpoints", "sizes", "fm", "output"];
+
+// Named set of widths, along with the `sizes` attribute and the transform parameters that go
+// with them
+#[derive(Clone, Debug, Deserialize)]
+pub struct Breakpoints {
+    pub widths: Vec<u32>,
+    pub sizes: Option<String>,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+// Configuration of srcsets. URLs start with `PUBLIC_URL`, or are relative to huffman when it
+// isn't set. Breakpoint sets are configured in `SRCSET_BREAKPOINTS` as a JSON object by name,
+// eg. `{"article": {"widths": [320, 640, 1280], "params": {"h": "400", "fit": "cover"}}}`.
+// When signing is enabled, srcsets are only built for those sets and with the transform
+// parameters configured on them, so that anyone can't sign any transform.
+#[derive(Clone, Debug, Default)]
+pub struct Srcsets {
+    _base_url: String,
+    _breakpoints: HashMap<String, Breakpoints>,
+}
+
+// URL of the image at a width
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub width: u32,
+    pub url: String,
+}
+
+// Candidates of the image in a format
+#[derive(Debug, Serialize)]
+pub struct Source {
+    pub format: &'static str,
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub srcset: String,
+    pub candidates: Vec<Candidate>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Srcset {
+    pub sizes: String,
+    // In the order of `fm`. The last one is the fallback for browsers that support none of the
+    // others.
+    pub sources: Vec<Source>,
+}
+
+fn invalid(name: &str, value: &str) -> Error {
+    Error::InvalidParameter(format!("Invalid value for {}: {}", name, value))
+}
+
+// Widths are sorted and deduplicated, as browsers pick from them by size anyway
+fn parse_widths(widths: &[u32]) -> Result<Vec<u32>, Error> {
+    let mut widths = widths.to_vec();
+    widths.sort_unstable();
+    widths.dedup();
+
+    if widths.is_empty() || widths.len() > MAX_WIDTHS {
+        return Err(Error::InvalidParameter(format!(
+            "widths must have between 1 and {} widths",
+            MAX_WIDTHS
+        )));
+    }
+    if widths
+        .iter()
+        .any(|width| !(1..=MAX_DIMENSION).contains(width))
+    {
+        return Err(Error::InvalidParameter(format!(
+            "widths must be between 1 and {}",
+            MAX_DIMENSION
+        )));
+    }
+
+    Ok(widths)
+}
+
+// Path segments are encoded one at a time, so that the slashes between them are kept
+fn encode_key(key: &str) -> String {
+    key.split('/')
+        .map(|segment| RawStr::new(segment).percent_encode().to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Srcsets {
+    pub fn from_env() -> Self {
+        let breakpoints = match env::var("SRCSET_BREAKPOINTS") {
+            Ok(value) if !value.trim().is_empty() => serde_json::from_str::<
+                HashMap<String, Breakpoints>,
+            >(&value)
+            .unwrap_or_else(|error| {
+                log::error!("Ignoring SRCSET_BREAKPOINTS as it isn't valid. {}", error);
+                HashMap::new()
+            }),
+            _ => HashMap::new(),
+        };
+
+        Srcsets {
+            _base_url: env::var("PUBLIC_URL")
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_default(),
+            _breakpoints: breakpoints,
+        }
+    }
+
+    // Srcset of the image at the key for the query parameters, eg.
+    // `?widths=320,640&fm=avif,webp&h=400&fit=cover`. The parameters of breakpoints are applied
+    // unless the query sets them. Every URL is signed when signing is enabled, in which case the
+    // widths must come from breakpoints and the query can only repeat their parameters.
+    pub fn build<'a>(
+        &self,
+        key: &str,
+        params: impl IntoIterator<Item = (&'a str, &'a str)>,
+        signer: &Signer,
+    ) -> Result<Srcset, Error> {
+        let (params, transform_params): (Vec<_>, Vec<_>) = params
+            .into_iter()
+            .partition(|(name, _)| PARAMS.contains(name));
+        if transform_params.iter().any(|(name, _)| *name == "w") {
+            return Err(Error::InvalidParameter(String::from(
+                "w can't be set, as it is set by widths",
+            )));
+        }
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(param, _)| *param == name)
+                .map(|(_, value)| *value)
+        };
+
+        if signer.is_enabled() && param("breakpoints").is_none() {
+            return Err(Error::Unauthorized(String::from(
+                "Signed srcsets need breakpoints instead of widths",
+            )));
+        }
+
+        let (widths, sizes, configured) = match (param("widths"), param("breakpoints")) {
+            (Some(widths), None) => {
+                let widths = widths
+                    .split(',')
+                    .map(|width| width.trim().parse::<u32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid("widths", widths))?;
+                (widths, None, None)
+            }
+            (None, Some(name)) => {
+                let breakpoints = self._breakpoints.get(name).ok_or_else(|| {
+                    Error::InvalidParameter(format!("Unknown breakpoints {}", name))
+                })?;
+                (
+                    breakpoints.widths.clone(),
+                    breakpoints.sizes.clone(),
+                    Some(&breakpoints.params),
+                )
+            }
+            _ => {
+                return Err(Error::InvalidParameter(String::from(
+                    "Either widths or breakpoints is required",
+                )))
+            }
+        };
+        let widths = parse_widths(&widths)?;
+
+        // Signing whatever value the query sets would let anyone sign any size, so the values
+        // have to be the ones configured
+        if signer.is_enabled() {
+            if let Some((name, value)) = transform_params.iter().find(|(name, value)| {
+                configured
+                    .and_then(|params| params.get(*name))
+                    .map(String::as_str)
+                    != Some(*value)
+            }) {
+                return Err(Error::Unauthorized(format!(
+                    "{}={} isn't configured for the breakpoints of signed srcsets",
+                    name, value
+                )));
+            }
+        }
+        let transform_params: Vec<(&str, &str)> = configured
+            .into_iter()
+            .flatten()
+            .filter(|(name, _)| !transform_params.iter().any(|(param, _)| param == name))
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(transform_params.iter().copied())
+            .collect();
+        let sizes = param("sizes")
+            .map(str::to_string)
+            .or(sizes)
+            .unwrap_or_else(|| String::from(DEFAULT_SIZES));
+
+        // Formats that end up the same, eg. `jpg` and `jpeg`, only get a source once
+        let mut seen = HashSet::new();
+        let mut sources = vec![];
+        for format in param("fm").unwrap_or("webp").split(',') {
+            let mut candidates = vec![];
+            let mut output = OutputFormat::default();
+            for width in &widths {
+                let width_param = width.to_string();
+                let transform = Transform::parse(
+                    transform_params
+                        .iter()
+                        .copied()
+                        .chain([("fm", format), ("w", width_param.as_str())]),
+                )?;
+                if matches!(transform.format, OutputFormat::Svg | OutputFormat::Json) {
+                    return Err(invalid("fm", format));
+                }
+                output = transform.format;
+
+                let mut url = format!(
+                    "{}/{}?{}",
+                    self._base_url,
+                    encode_key(key),
+                    transform.to_query()
+                );
+                if let Some(signature) = signer.sign(key, &transform) {
+                    url.push_str(&format!("&s={}", signature));
+                }
+                candidates.push(Candidate { width: *width, url });
+            }
+            if !seen.insert(output.extension()) {
+                continue;
+            }
+
+            sources.push(Source {
+                format: output.extension(),
+                content_type: output.content_type().to_string(),
+                srcset: candidates
+                    .iter()
+                    .map(|candidate| format!("{} {}w", candidate.url, candidate.width))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                candidates,
+            });
+        }
+
+        Ok(Srcset { sizes, sources })
+    }
+}
+
+// Whether the `output` parameter asks for markup instead of JSON
+pub fn is_html(output: Option<&str>) -> Result<bool, Error> {
+    match output {
+        None | Some("json") => Ok(false),
+        Some("html") => Ok(true),
+        Some(value) => Err(invalid("output", value)),
+    }
+}
+
+impl Srcset {
+    // `<img>` with the srcset, inside a `<picture>` with a `<source>` for every other format
+    // when there are several. The largest width of the last format is the `src`.
+    pub fn to_html(&self) -> String {
+        let sizes = escape(&self.sizes);
+        let (fallback, sources) = match self.sources.split_last() {
+            Some(split) => split,
+            None => return String::new(),
+        };
+        let src = fallback
+            .candidates
+            .last()
+            .map(|candidate| escape(&candidate.url))
+            .unwrap_or_default();
+        let img = format!(
+            "<img src=\"{}\" srcset=\"{}\" sizes=\"{}\">",
+            src,
+            escape(&fallback.srcset),
+            sizes
+        );
+        if sources.is_empty() {
+            return img;
+        }
+
+        let mut html = String::from("<picture>");
+        for source in sources {
+            html.push_str(&format!(
+                "<source type=\"{}\" srcset=\"{}\" sizes=\"{}\">",
+                source.content_type,
+                escape(&source.srcset),
+                sizes
+            ));
+        }
+        html.push_str(&img);
+        html.push_str("</picture>");
+        html
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn srcsets() -> Srcsets {
+        Srcsets {
+            _base_url: String::from("https://images.example.com"),
+            _breakpoints: HashMap::from([(
+                String::from("article"),
+                Breakpoints {
+                    widths: vec![640, 320],
+                    sizes: Some(String::from("(min-width: 800px) 720px, 100vw")),
+                    params: HashMap::from([(String::from("h"), String::from("400"))]),
+                },
+            )]),
+        }
+    }
+
+    #[test]
+    fn builds_srcsets() {
+        let srcset = srcsets()
+            .build(
+                "photos/a b.jpg",
+                [
+                    ("widths", "640,320,640"),
+                    ("fm", "avif,webp"),
+                    ("fit", "cover"),
+                    ("h", "400"),
+                ],
+                &Signer::default(),
+            )
+            .unwrap();
+
+        assert_eq!(srcset.sizes, "100vw");
+        assert_eq!(srcset.sources.len(), 2);
+        assert_eq!(srcset.sources[0].content_type, "image/avif");
+        assert_eq!(
+            srcset.sources[0].srcset,
+            "https://images.example.com/photos/a%20b.jpg?w=320&h=400&fit=cover&fm=avif 320w, \
+             https://images.example.com/photos/a%20b.jpg?w=640&h=400&fit=cover&fm=avif 640w"
+        );
+        // WebP is the default format, so it isn't in the query
+        assert_eq!(
+            srcset.sources[1].candidates[0].url,
+            "https://images.example.com/photos/a%20b.jpg?w=320&h=400&fit=cover"
+        );
+        assert_eq!(
+            srcset.to_html(),
+            "<picture><source type=\"image/avif\" srcset=\"https://images.example.com/photos/a%20b.jpg?w=320&amp;h=400&amp;fit=cover&amp;fm=avif 320w, https://images.example.com/photos/a%20b.jpg?w=640&amp;h=400&amp;fit=cover&amp;fm=avif 640w\" sizes=\"100vw\">\
+             <img src=\"https://images.example.com/photos/a%20b.jpg?w=640&amp;h=400&amp;fit=cover\" srcset=\"https://images.example.com/photos/a%20b.jpg?w=320&amp;h=400&amp;fit=cover 320w, https://images.example.com/photos/a%20b.jpg?w=640&amp;h=400&amp;fit=cover 640w\" sizes=\"100vw\"></picture>"
+        );
+
+        let srcset = srcsets()
+            .build(
+                "a.jpg",
+                [("breakpoints", "article"), ("fm", "jpeg")],
+                &Signer::default(),
+            )
+            .unwrap();
+        assert_eq!(srcset.sizes, "(min-width: 800px) 720px, 100vw");
+        assert_eq!(
+            srcset.sources[0].srcset,
+            "https://images.example.com/a.jpg?w=320&h=400&fm=jpeg 320w, \
+             https://images.example.com/a.jpg?w=640&h=400&fm=jpeg 640w"
+        );
+        assert!(srcset.to_html().starts_with("<img src="));
+
+        // The query overrides the parameters of breakpoints when URLs aren't signed
+        let srcset = srcsets()
+            .build(
+                "a.jpg",
+                [("breakpoints", "article"), ("h", "200")],
+                &Signer::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            srcset.sources[0].candidates[0].url,
+            "https://images.example.com/a.jpg?w=320&h=200"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_srcsets() {
+        let build = |params: &[(&str, &str)]| {
+            srcsets().build("a.jpg", params.iter().copied(), &Signer::default())
+        };
+
+        assert!(build(&[]).is_err());
+        assert!(build(&[("widths", "320"), ("breakpoints", "article")]).is_err());
+        assert!(build(&[("breakpoints", "hero")]).is_err());
+        assert!(build(&[("widths", "320,abc")]).is_err());
+        assert!(build(&[("widths", "0")]).is_err());
+        assert!(build(&[("widths", "10000")]).is_err());
+        let widths: Vec<String> = (1..=21).map(|width| width.to_string()).collect();
+        assert!(build(&[("widths", widths.join(",").as_str())]).is_err());
+        assert!(build(&[("widths", "320"), ("w", "100")]).is_err());
+        assert!(build(&[("widths", "320"), ("fm", "webp,json")]).is_err());
+        assert!(build(&[("widths", "320"), ("fm", "bmp")]).is_err());
+        assert!(build(&[("widths", "320"), ("fit", "squash")]).is_err());
+    }
+
+    #[test]
+    fn skips_formats_that_end_up_the_same() {
+        let formats = |fm: &str| {
+            srcsets()
+                .build("a.jpg", [("widths", "320"), ("fm", fm)], &Signer::default())
+                .unwrap()
+                .sources
+                .iter()
+                .map(|source| source.format)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(formats("avif,webp,avif"), ["avif", "webp"]);
+        assert_eq!(formats("jpg,webp,jpeg"), ["jpeg", "webp"]);
+    }
+
+    #[test]
+    fn signs_configured_breakpoints_only() {
+        let signer = Signer::new(b"secret");
+        let build =
+            |params: &[(&str, &str)]| srcsets().build("a.jpg", params.iter().copied(), &signer);
+
+        let transform = Transform::from_query("w=320&h=400&fm=avif").unwrap();
+        let url = format!(
+            "https://images.example.com/a.jpg?w=320&h=400&fm=avif&s={}",
+            signer.sign("a.jpg", &transform).unwrap()
+        );
+        let srcset = build(&[("breakpoints", "article"), ("fm", "avif")]).unwrap();
+        assert_eq!(srcset.sources[0].candidates[0].url, url);
+        let srcset = build(&[("breakpoints", "article"), ("h", "400"), ("fm", "avif")]).unwrap();
+        assert_eq!(srcset.sources[0].candidates[0].url, url);
+
+        // Anyone could sign any width or parameter otherwise
+        assert!(matches!(
+            build(&[("widths", "320")]),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            build(&[("breakpoints", "article"), ("txt", "Sale")]),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            build(&[("breakpoints", "article"), ("h", "5000")]),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            build(&[("breakpoints", "article"), ("fit", "cover")]),
+            Err(Error::Unauthorized(_))
+        ));
+        assert!(matches!(
+            build(&[("breakpoints", "hero")]),
+            Err(Error::InvalidParameter(_))
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::error::Error;
+use xmlparser::{ElementEnd, Token, Tokenizer};
+
+// Elements that can run scripts or embed other documents, removed along with their children
+const BLOCKED_ELEMENTS: [&str; 6] = [
+    "script",
+    "foreignObject",
+    "iframe",
+    "object",
+    "embed",
+    "handler",
+];
+
+// Whether the value can load a resource from outside the document or run a script. Character
+// references and CSS escapes are rejected as they can hide either from this check, eg.
+// `\75rl(` is read as `url(` by browsers.
+fn references_external(value: &str) -> bool {
+    let value = value.to_ascii_lowercase();
+    if ["javascript:", "@import", "image-set(", "&#", "\\"]
+        .iter()
+        .any(|pattern| value.contains(pattern))
+    {
+        return true;
+    }
+
+    value.match_indices("url(").any(|(index, _)| {
+        !value[index + 4..]
+            .trim_start_matches(|c: char| c.is_whitespace() || c == '"' || c == '\'')
+            .starts_with('#')
+    })
+}
+
+fn is_allowed_attribute(name: &str, value: &str) -> bool {
+    // Event handlers, eg. onload
+    if name.to_ascii_lowercase().starts_with("on") {
+        return false;
+    }
+
+    // Links may only point within the document or embed images inline
+    if name == "href" || name == "src" {
+        let value = value.trim_start();
+        if !value.starts_with('#') && !value.starts_with("data:image/") {
+            return false;
+        }
+    }
+
+    !references_external(value)
+}
+
+// Removes scripts, event handlers, external references, DTDs and processing instructions, so
+// that SVGs can be served as they are or handed to librsvg without loading anything else.
+pub fn sanitize(buffer: &[u8]) -> Result<Vec<u8>, Error> {
+    let text = std::str::from_utf8(buffer)
+        .map_err(|_| Error::DecodeFailed(String::from("SVG is not valid UTF-8")))?
+        .trim_start_matches('\u{feff}');
+
+    let malformed = || Error::DecodeFailed(String::from("SVG is not well-formed"));
+    let mut output = String::with_capacity(text.len());
+    // Prefix and name of the open elements and of the element whose start tag is being read
+    let mut open = vec![];
+    let mut current = ("", "");
+    // Depth of the element that is being removed along with its children
+    let mut removed_at = None;
+    let mut in_style = false;
+
+    for token in Tokenizer::from(text) {
+        let token = token
+            .map_err(|error| Error::DecodeFailed(format!("Could not parse SVG: {}", error)))?;
+        let removing = removed_at.is_some();
+
+        match token {
+            Token::Declaration { span, .. } => output.push_str(span.as_str()),
+            Token::ElementStart {
+                prefix,
+                local,
+                span,
+            } => {
+                current = (prefix.as_str(), local.as_str());
+                if removing {
+                    continue;
+                }
+                if BLOCKED_ELEMENTS.contains(&local.as_str()) {
+                    removed_at = Some(open.len());
+                    continue;
+                }
+                in_style = local.as_str() == "style";
+                output.push_str(span.as_str());
+            }
+            Token::Attribute {
+                local, value, span, ..
+            } if !removing && is_allowed_attribute(local.as_str(), value.as_str()) => {
+                output.push(' ');
+                output.push_str(span.as_str());
+            }
+            Token::ElementEnd { end, span } => {
+                match end {
+                    ElementEnd::Open => open.push(current),
+                    ElementEnd::Close(prefix, local) => {
+                        if open.pop() != Some((prefix.as_str(), local.as_str())) {
+                            return Err(malformed());
+                        }
+                        in_style = false;
+                    }
+                    ElementEnd::Empty => {}
+                }
+
+                if !removing {
+                    output.push_str(span.as_str());
+                } else if removed_at == Some(open.len()) && !matches!(end, ElementEnd::Open) {
+                    removed_at = None;
+                }
+            }
+            Token::Text { text } | Token::Cdata { span: text, .. }
+                if !removing && (!in_style || !references_external(text.as_str())) =>
+            {
+                output.push_str(text.as_str());
+            }
+            // Removed attributes and text, comments, processing instructions and DTDs, which can
+            // declare external entities
+            _ => {}
+        }
+    }
+
+    if !open.is_empty() {
+        return Err(malformed());
+    }
+
+    Ok(output.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized(svg: &str) -> String {
+        String::from_utf8(sanitize(svg.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keeps_safe_documents() {
+        let svg = r##"<?xml version="1.0"?><svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 10 10"><defs><linearGradient id="a"/></defs><rect fill="url(#a)" width="10" height="10"/><use href="#a"/><style>rect { fill: red }</style></svg>"##;
+        assert_eq!(sanitized(svg), svg);
+    }
+
+    #[test]
+    fn removes_scripts_and_external_references() {
+        let svg = r#"<!DOCTYPE svg [<!ENTITY x SYSTEM "file:///etc/passwd">]><!-- a --><svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)"><script>alert(1)</script><foreignObject><div><p>a</p></div></foreignObject><image href="https://example.com/a.png"/><a href="javascript:alert(1)"><rect style="fill: url( 'https://example.com/a.svg#b')"/></a><style>@import url(https://example.com/a.css);</style></svg>"#;
+        assert_eq!(
+            sanitized(svg),
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><image/><a><rect/></a><style></style></svg>"#
+        );
+
+        // CSS escapes and image sets load resources without a literal url( or @import
+        let svg = r#"<svg><style>rect{fill:\75rl(https://example.com/x)}</style><style>@\69mport "https://example.com/a.css";</style><style>rect{fill:-webkit-image-set("https://example.com/a.png" 1x)}</style><rect style="fill:\75rl(https://example.com/x)"/><rect style="background:image-set('https://example.com/a.png' 1x)"/><rect style="fill: red"/></svg>"#;
+        assert_eq!(
+            sanitized(svg),
+            r#"<svg><style></style><style></style><style></style><rect/><rect/><rect style="fill: red"/></svg>"#
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_documents() {
+        assert!(sanitize(b"<svg><rect></svg>").is_err());
+        assert!(sanitize(b"<svg><script></g>alert(1)</script></svg>").is_err());
+        assert!(sanitize(b"\xFF\xFE<svg/>").is_err());
+    }
+}
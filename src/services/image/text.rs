@@ -0,0 +1,106 @@
+use super::transform::{Color, Position, Transform};
+use crate::error::Error;
+use libvips::ops::{self, Align, BandFormat};
+use libvips::VipsImage;
+
+// Font size in pixels when `txt_size` isn't set
+const DEFAULT_SIZE: u32 = 32;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not render text: {}", error))
+}
+
+// libvips reads the text as Pango markup, so markup in the text is escaped
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// Captions sit at the bottom of the image unless `txt_pos` is set
+pub fn position(transform: &Transform) -> Position {
+    transform.text_position.unwrap_or(Position::S)
+}
+
+// Space between the text and the edges of the image, half of the font size
+pub fn margin(transform: &Transform) -> u32 {
+    transform.text_size.unwrap_or(DEFAULT_SIZE) / 2
+}
+
+// Renders the text of the transform as an overlay for images the size of the template, wrapped
+// to fit within the margins and aligned with its position. Text that doesn't fit is cut off.
+pub fn render(transform: &Transform, template: &VipsImage) -> Result<Option<VipsImage>, Error> {
+    let text = match &transform.text {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+
+    let margin = margin(transform) as i32;
+    let max_width = template.get_width() - 2 * margin;
+    let max_height = template.get_height() - 2 * margin;
+    if max_width < 1 || max_height < 1 {
+        return Ok(None);
+    }
+
+    let align = match position(transform) {
+        Position::W | Position::Nw | Position::Sw => Align::Low,
+        Position::Center | Position::N | Position::S => Align::Centre,
+        Position::E | Position::Ne | Position::Se => Align::High,
+    };
+    // At 72 DPI the font size in points is the size in pixels
+    let mask = ops::text_with_opts(
+        &escape(text),
+        &ops::TextOptions {
+            font: format!(
+                "{} {}",
+                transform.text_font.family(),
+                transform.text_size.unwrap_or(DEFAULT_SIZE)
+            ),
+            width: transform
+                .text_width
+                .map_or(max_width, |width| (width as i32).min(max_width)),
+            align,
+            dpi: 72,
+            ..ops::TextOptions::default()
+        },
+    )
+    .map_err(failed)?;
+
+    // Words longer than the width aren't wrapped
+    let width = mask.get_width().min(max_width);
+    let height = mask.get_height().min(max_height);
+    let mask = ops::extract_area(&mask, 0, 0, width, height).map_err(failed)?;
+
+    // The fill is made from the template so that it is sRGB like the images it is composited on
+    let color = transform.text_color.unwrap_or(Color::BLACK);
+    let fill = VipsImage::new_from_image(
+        template,
+        &[color.red as f64, color.green as f64, color.blue as f64],
+    )
+    .and_then(|fill| ops::extract_area(&fill, 0, 0, width, height))
+    .and_then(|fill| ops::cast(&fill, BandFormat::Uchar))
+    .map_err(failed)?;
+    let alpha = if color.alpha == 255 {
+        mask
+    } else {
+        VipsImage::new_from_image(&mask, &[color.alpha as f64 / 255.0])
+            .and_then(|opacity| ops::multiply(&mask, &opacity))
+            .and_then(|alpha| ops::cast(&alpha, BandFormat::Uchar))
+            .map_err(failed)?
+    };
+
+    ops::bandjoin(&mut [fill, alpha]).map(Some).map_err(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(
+            escape("<b>Tom & Jerry</b>"),
+            "&lt;b&gt;Tom &amp; Jerry&lt;/b&gt;"
+        );
+    }
+}
@@ -0,0 +1,1166 @@
+use crate::error::Error;
+use rocket::http::{Accept, ContentType, RawStr};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+// Largest width or height that can be requested
+pub const MAX_DIMENSION: u32 = 8192;
+
+// Highest density in DPI that SVG and PDF sources can be rasterized at
+pub const MAX_DENSITY: u32 = 600;
+
+// Largest margin in pixels between a watermark and the edges of the image
+pub const MAX_MARGIN: u32 = 1000;
+
+// Largest difference from the background color that trimmed borders can have
+pub const MAX_TRIM_THRESHOLD: u32 = 255;
+
+// Largest font size in pixels and longest text in characters that can be rendered
+pub const MAX_TEXT_SIZE: u32 = 512;
+pub const MAX_TEXT_LENGTH: usize = 500;
+
+// Most colors that a palette can have
+pub const MAX_PALETTE_SIZE: u32 = 16;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Fit {
+    // Fit within the box, keeping the aspect ratio
+    #[default]
+    Inside,
+    // Fit within the box and pad the rest of it, so that the output is exactly the box size
+    Contain,
+    // Fill the box, cropping the edges that don't fit
+    Cover,
+    // Stretch to fill the box
+    Fill,
+}
+
+impl Fit {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "inside" => Some(Fit::Inside),
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "fill" => Some(Fit::Fill),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Fit::Inside => "inside",
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flip {
+    Horizontal,
+    Vertical,
+}
+
+impl Flip {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "h" => Some(Flip::Horizontal),
+            "v" => Some(Flip::Vertical),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Flip::Horizontal => "h",
+            Flip::Vertical => "v",
+        }
+    }
+}
+
+// Where a watermark is placed, as a compass direction from the center of the image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Position {
+    Center,
+    N,
+    Ne,
+    E,
+    #[default]
+    Se,
+    S,
+    Sw,
+    W,
+    Nw,
+}
+
+impl Position {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "center" => Some(Position::Center),
+            "n" => Some(Position::N),
+            "ne" => Some(Position::Ne),
+            "e" => Some(Position::E),
+            "se" => Some(Position::Se),
+            "s" => Some(Position::S),
+            "sw" => Some(Position::Sw),
+            "w" => Some(Position::W),
+            "nw" => Some(Position::Nw),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Position::Center => "center",
+            Position::N => "n",
+            Position::Ne => "ne",
+            Position::E => "e",
+            Position::Se => "se",
+            Position::S => "s",
+            Position::Sw => "sw",
+            Position::W => "w",
+            Position::Nw => "nw",
+        }
+    }
+}
+
+// Fonts that text is rendered with. The Docker images install the DejaVu fonts.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Font {
+    #[default]
+    Sans,
+    SansBold,
+    Serif,
+    SerifBold,
+    Mono,
+    MonoBold,
+}
+
+impl Font {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sans" => Some(Font::Sans),
+            "sans-bold" => Some(Font::SansBold),
+            "serif" => Some(Font::Serif),
+            "serif-bold" => Some(Font::SerifBold),
+            "mono" => Some(Font::Mono),
+            "mono-bold" => Some(Font::MonoBold),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Font::Sans => "sans",
+            Font::SansBold => "sans-bold",
+            Font::Serif => "serif",
+            Font::SerifBold => "serif-bold",
+            Font::Mono => "mono",
+            Font::MonoBold => "mono-bold",
+        }
+    }
+
+    // Pango font family and style
+    pub fn family(&self) -> &'static str {
+        match self {
+            Font::Sans => "DejaVu Sans",
+            Font::SansBold => "DejaVu Sans Bold",
+            Font::Serif => "DejaVu Serif",
+            Font::SerifBold => "DejaVu Serif Bold",
+            Font::Mono => "DejaVu Sans Mono",
+            Font::MonoBold => "DejaVu Sans Mono Bold",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color {
+        red: 0,
+        green: 0,
+        blue: 0,
+        alpha: 255,
+    };
+
+    // Hex colors with an optional alpha, eg. `f80`, `ff8800` or `ff880080`, with or without `#`,
+    // or `rgb(255,136,0)` and `rgba(255,136,0,0.5)`
+    fn parse(value: &str) -> Option<Self> {
+        if let Some(arguments) = value
+            .strip_prefix("rgba(")
+            .or_else(|| value.strip_prefix("rgb("))
+        {
+            return Color::parse_function(value.starts_with("rgba("), arguments);
+        }
+
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        let digits: Vec<u8> = match hex.len() {
+            3 | 4 => hex
+                .chars()
+                .map(|c| c.to_digit(16).map(|digit| digit as u8 * 17))
+                .collect::<Option<_>>()?,
+            6 | 8 => (0..hex.len())
+                .step_by(2)
+                .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+                .collect::<Option<_>>()?,
+            _ => return None,
+        };
+
+        Some(Color {
+            red: digits[0],
+            green: digits[1],
+            blue: digits[2],
+            alpha: digits.get(3).copied().unwrap_or(255),
+        })
+    }
+
+    fn parse_function(has_alpha: bool, arguments: &str) -> Option<Self> {
+        let arguments: Vec<&str> = arguments
+            .strip_suffix(')')?
+            .split(',')
+            .map(str::trim)
+            .collect();
+        if arguments.len() != if has_alpha { 4 } else { 3 } {
+            return None;
+        }
+
+        let channel = |index: usize| arguments[index].parse::<u8>().ok();
+        let alpha = match arguments.get(3) {
+            Some(alpha) => {
+                let alpha = alpha
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|alpha| (0.0..=1.0).contains(alpha))?;
+                (alpha * 255.0).round() as u8
+            }
+            None => 255,
+        };
+
+        Some(Color {
+            red: channel(0)?,
+            green: channel(1)?,
+            blue: channel(2)?,
+            alpha,
+        })
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.alpha == 255
+    }
+
+    pub fn name(&self) -> String {
+        let mut name = format!("{:02x}{:02x}{:02x}", self.red, self.green, self.blue);
+        if self.alpha != 255 {
+            let _ = write!(name, "{:02x}", self.alpha);
+        }
+        name
+    }
+}
+
+// Line drawn around the image, outside of its edges
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Border {
+    pub width: u32,
+    pub color: Color,
+}
+
+impl Border {
+    // A width in pixels, optionally followed by a color, eg. `4` or `4,ff0000`. Borders are
+    // black by default.
+    fn parse(value: &str) -> Option<Self> {
+        let (width, color) = match value.split_once(',') {
+            Some((width, color)) => (width, Color::parse(color)?),
+            None => (value, Color::BLACK),
+        };
+        let width = width
+            .parse::<u32>()
+            .ok()
+            .filter(|width| (1..=MAX_MARGIN).contains(width))?;
+
+        Some(Border { width, color })
+    }
+
+    fn name(&self) -> String {
+        format!("{},{}", self.width, self.color.name())
+    }
+}
+
+// Area of the source image to keep, in pixels of the image once it is rotated upright
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    // `x,y,width,height`, eg. `10,20,300,200`
+    fn parse(value: &str) -> Option<Self> {
+        let numbers = value
+            .split(',')
+            .map(|number| number.trim().parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+
+        match numbers[..] {
+            [x, y, width, height] if width > 0 && height > 0 => Some(Rect {
+                x,
+                y,
+                width,
+                height,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        format!("{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    // Largest circle that fits in the image, in its center
+    Circle,
+}
+
+impl Mask {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "circle" => Some(Mask::Circle),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Mask::Circle => "circle",
+        }
+    }
+}
+
+// Metadata kept in the output. Pixels are always converted to sRGB and the EXIF orientation is
+// always applied.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Metadata {
+    // Removes all metadata along with the ICC profile, as sRGB is assumed without one
+    #[default]
+    Strip,
+    // Keeps the Artist and Copyright EXIF tags along with the sRGB profile. Rights in IPTC or
+    // XMP metadata are removed with the rest.
+    Copyright,
+    // Keeps only the sRGB ICC profile
+    Icc,
+}
+
+impl Metadata {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "strip" => Some(Metadata::Strip),
+            "copyright" => Some(Metadata::Copyright),
+            "icc" => Some(Metadata::Icc),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Metadata::Strip => "strip",
+            Metadata::Copyright => "copyright",
+            Metadata::Icc => "icc",
+        }
+    }
+
+    pub fn from_env(name: &str) -> Option<Self> {
+        env::var(name)
+            .ok()
+            .and_then(|value| Metadata::parse(&value))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Webp,
+    Avif,
+    Jpeg,
+    Png,
+    // Requires libvips to be built with libjxl
+    Jxl,
+    // Serves SVG sources as SVGs after sanitizing them, instead of rasterizing them
+    Svg,
+    // Serves the dominant color and palette of the output instead of its pixels
+    Json,
+}
+
+impl OutputFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "webp" => Some(OutputFormat::Webp),
+            "avif" => Some(OutputFormat::Avif),
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            "jxl" => Some(OutputFormat::Jxl),
+            "svg" => Some(OutputFormat::Svg),
+            "json" => Some(OutputFormat::Json),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::Jxl => "jxl",
+            OutputFormat::Svg => "svg",
+            OutputFormat::Json => "json",
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            OutputFormat::Webp => ContentType::WEBP,
+            OutputFormat::Avif => ContentType::AVIF,
+            OutputFormat::Jpeg => ContentType::JPEG,
+            OutputFormat::Png => ContentType::PNG,
+            OutputFormat::Jxl => ContentType::new("image", "jxl"),
+            OutputFormat::Svg => ContentType::SVG,
+            OutputFormat::Json => ContentType::JSON,
+        }
+    }
+
+    // Formats that keep every frame of animated images. Other formats only get the first frame.
+    pub fn supports_animation(&self) -> bool {
+        matches!(self, OutputFormat::Webp)
+    }
+}
+
+// Output formats that are picked from the Accept header of requests that don't set `fm`. Only
+// JPEG XL is offered, and only when enabled, as it can't be encoded by every libvips build.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Negotiation {
+    _jxl: bool,
+}
+
+impl Negotiation {
+    pub fn from_env() -> Self {
+        Negotiation {
+            _jxl: env::var("IMAGE_NEGOTIATE_JXL")
+                .map(|value| value == "true")
+                .unwrap_or(false),
+        }
+    }
+
+    // Whether responses to requests without `fm` depend on the Accept header
+    pub fn is_enabled(&self) -> bool {
+        self._jxl
+    }
+
+    pub fn negotiate(&self, accept: Option<&Accept>) -> Option<OutputFormat> {
+        let accepts_jxl = accept.is_some_and(|accept| {
+            accept
+                .media_types()
+                .any(|media_type| media_type.top() == "image" && media_type.sub() == "jxl")
+        });
+
+        if self._jxl && accepts_jxl {
+            Some(OutputFormat::Jxl)
+        } else {
+            None
+        }
+    }
+}
+
+// Operations applied to a source image, parsed from the query string of a request, eg.
+// `?w=300&h=200&fit=cover&fm=avif`. Unknown parameters are ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Transform {
+    // Crops the source before anything else is applied
+    pub rect: Option<Rect>,
+    // Removes borders of the same color as the top left pixel, that differ from it by at most
+    // this threshold
+    pub trim: Option<u32>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub format: OutputFormat,
+    // Renders a single frame of animated images as a still image
+    pub frame: Option<u32>,
+    // Page of PDF sources to rasterize, starting from 1
+    pub page: Option<u32>,
+    // DPI that SVG and PDF sources are rasterized at, 72 by default
+    pub density: Option<u32>,
+    // Degrees to rotate clockwise, after the image is rotated upright using its EXIF orientation
+    pub rotation: u32,
+    pub flip: Option<Flip>,
+    // Overrides the metadata policy configured for the pool
+    pub metadata: Option<Metadata>,
+    // Sigma of the gaussian used to sharpen or blur the image
+    pub sharpen: Option<f64>,
+    pub blur: Option<f64>,
+    // Percentages between -100 and 100. -100 saturation makes the image greyscale.
+    pub brightness: Option<f64>,
+    pub contrast: Option<f64>,
+    pub saturation: Option<f64>,
+    // Values above 1 brighten the midtones, values below 1 darken them
+    pub gamma: Option<f64>,
+    // Name of the watermark preset to composite over the image, along with overrides of the
+    // placement it configures
+    pub watermark: Option<String>,
+    pub watermark_position: Option<Position>,
+    pub watermark_margin: Option<u32>,
+    pub watermark_opacity: Option<f64>,
+    // Width of the watermark relative to the width of the image
+    pub watermark_scale: Option<f64>,
+    // Caption rendered over the image, wrapped to fit the width
+    pub text: Option<String>,
+    pub text_font: Font,
+    // Font size in pixels
+    pub text_size: Option<u32>,
+    pub text_color: Option<Color>,
+    pub text_position: Option<Position>,
+    // Width in pixels that the text is wrapped at, the width of the image by default
+    pub text_width: Option<u32>,
+    // Pixels of background added on every side of the image
+    pub padding: Option<u32>,
+    // Fills padding and transparent corners, and the transparent pixels of JPEGs. Without it
+    // padding is transparent and JPEGs are flattened onto white.
+    pub background: Option<Color>,
+    pub border: Option<Border>,
+    // Radius of rounded corners in pixels
+    pub radius: Option<u32>,
+    pub mask: Option<Mask>,
+    // Colors in the palette of `fm=json` outputs
+    pub palette: Option<u32>,
+}
+
+fn invalid(name: &str, value: &str) -> Error {
+    Error::InvalidParameter(format!("Invalid value for {}: {}", name, value))
+}
+
+fn parse_range(name: &str, value: &str, max: u32) -> Result<u32, Error> {
+    match value.parse::<u32>() {
+        Ok(number) if (1..=max).contains(&number) => Ok(number),
+        _ => Err(Error::InvalidParameter(format!(
+            "{} must be between 1 and {}",
+            name, max
+        ))),
+    }
+}
+
+// Parses a number within the range. Values that leave the image as it is are skipped, so that
+// they don't change the cache key.
+fn parse_float(
+    name: &str,
+    value: &str,
+    range: RangeInclusive<f64>,
+    neutral: f64,
+) -> Result<Option<f64>, Error> {
+    match value.parse::<f64>() {
+        Ok(number) if number == neutral => Ok(None),
+        Ok(number) if range.contains(&number) => Ok(Some(number)),
+        _ => Err(Error::InvalidParameter(format!(
+            "{} must be between {} and {}",
+            name,
+            range.start(),
+            range.end()
+        ))),
+    }
+}
+
+fn parse_fraction(name: &str, value: &str, min: f64) -> Result<f64, Error> {
+    match value.parse::<f64>() {
+        Ok(number) if (min..=1.0).contains(&number) => Ok(number),
+        _ => Err(Error::InvalidParameter(format!(
+            "{} must be between {} and 1",
+            name, min
+        ))),
+    }
+}
+
+impl Transform {
+    pub fn parse<'a>(params: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self, Error> {
+        let mut transform = Transform::default();
+
+        for (name, value) in params {
+            match name {
+                "rect" => {
+                    transform.rect = Some(Rect::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "trim" => transform.trim = Some(parse_range(name, value, MAX_TRIM_THRESHOLD)?),
+                "w" => transform.width = Some(parse_range(name, value, MAX_DIMENSION)?),
+                "h" => transform.height = Some(parse_range(name, value, MAX_DIMENSION)?),
+                "fit" => transform.fit = Fit::parse(value).ok_or_else(|| invalid(name, value))?,
+                "fm" => {
+                    transform.format =
+                        OutputFormat::parse(value).ok_or_else(|| invalid(name, value))?
+                }
+                "frame" => transform.frame = Some(value.parse().map_err(|_| invalid(name, value))?),
+                "page" => {
+                    let page = value.parse().ok().filter(|page| *page > 0);
+                    transform.page = Some(page.ok_or_else(|| invalid(name, value))?)
+                }
+                "density" => transform.density = Some(parse_range(name, value, MAX_DENSITY)?),
+                "rot" => {
+                    transform.rotation = match value {
+                        "0" | "90" | "180" | "270" => value.parse().unwrap_or_default(),
+                        _ => return Err(invalid(name, value)),
+                    }
+                }
+                "sharp" => transform.sharpen = parse_float(name, value, 0.1..=10.0, 0.0)?,
+                "blur" => transform.blur = parse_float(name, value, 0.3..=100.0, 0.0)?,
+                "bri" => transform.brightness = parse_float(name, value, -100.0..=100.0, 0.0)?,
+                "con" => transform.contrast = parse_float(name, value, -100.0..=100.0, 0.0)?,
+                "sat" => transform.saturation = parse_float(name, value, -100.0..=100.0, 0.0)?,
+                "gam" => transform.gamma = parse_float(name, value, 0.1..=10.0, 1.0)?,
+                "meta" => {
+                    transform.metadata =
+                        Some(Metadata::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "flip" => {
+                    transform.flip = Some(Flip::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "wm" => {
+                    let is_name = !value.is_empty()
+                        && value
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+                    if !is_name {
+                        return Err(invalid(name, value));
+                    }
+                    transform.watermark = Some(value.to_string())
+                }
+                "wm_pos" => {
+                    transform.watermark_position =
+                        Some(Position::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "wm_margin" => {
+                    let margin = value.parse().ok().filter(|margin| *margin <= MAX_MARGIN);
+                    transform.watermark_margin = Some(margin.ok_or_else(|| {
+                        Error::InvalidParameter(format!(
+                            "{} must be between 0 and {}",
+                            name, MAX_MARGIN
+                        ))
+                    })?)
+                }
+                "wm_opacity" => {
+                    transform.watermark_opacity = Some(parse_fraction(name, value, 0.0)?)
+                }
+                "wm_scale" => transform.watermark_scale = Some(parse_fraction(name, value, 0.01)?),
+                "txt" => {
+                    let length = value.chars().count();
+                    if length == 0 || length > MAX_TEXT_LENGTH {
+                        return Err(Error::InvalidParameter(format!(
+                            "{} must be between 1 and {} characters",
+                            name, MAX_TEXT_LENGTH
+                        )));
+                    }
+                    transform.text = Some(value.to_string())
+                }
+                "txt_font" => {
+                    transform.text_font = Font::parse(value).ok_or_else(|| invalid(name, value))?
+                }
+                "txt_size" => transform.text_size = Some(parse_range(name, value, MAX_TEXT_SIZE)?),
+                "txt_color" => {
+                    transform.text_color =
+                        Some(Color::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "txt_pos" => {
+                    transform.text_position =
+                        Some(Position::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "txt_width" => {
+                    transform.text_width = Some(parse_range(name, value, MAX_DIMENSION)?)
+                }
+                "pad" => {
+                    let padding = value.parse().ok().filter(|padding| *padding <= MAX_MARGIN);
+                    let padding = padding.ok_or_else(|| {
+                        Error::InvalidParameter(format!(
+                            "{} must be between 0 and {}",
+                            name, MAX_MARGIN
+                        ))
+                    })?;
+                    transform.padding = Some(padding).filter(|padding| *padding > 0)
+                }
+                "bg" => {
+                    transform.background =
+                        Some(Color::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "border" => {
+                    transform.border =
+                        Some(Border::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "radius" => transform.radius = Some(parse_range(name, value, MAX_DIMENSION)?),
+                "mask" => {
+                    transform.mask = Some(Mask::parse(value).ok_or_else(|| invalid(name, value))?)
+                }
+                "palette" => transform.palette = Some(parse_range(name, value, MAX_PALETTE_SIZE)?),
+                _ => {}
+            }
+        }
+
+        // JPEG can't keep transparent pixels, so images that need them are served as PNG instead
+        if transform.format == OutputFormat::Jpeg && transform.needs_transparency() {
+            transform.format = OutputFormat::Png;
+        }
+
+        let styles_text = transform.text_font != Font::default()
+            || transform.text_size.is_some()
+            || transform.text_color.is_some()
+            || transform.text_position.is_some()
+            || transform.text_width.is_some();
+        if styles_text && transform.text.is_none() {
+            return Err(Error::InvalidParameter(String::from(
+                "txt_font, txt_size, txt_color, txt_pos and txt_width require txt",
+            )));
+        }
+
+        if transform.palette.is_some() && transform.format != OutputFormat::Json {
+            return Err(Error::InvalidParameter(String::from(
+                "palette requires fm=json",
+            )));
+        }
+
+        let overrides_watermark = transform.watermark_position.is_some()
+            || transform.watermark_margin.is_some()
+            || transform.watermark_opacity.is_some()
+            || transform.watermark_scale.is_some();
+        if overrides_watermark && transform.watermark.is_none() {
+            return Err(Error::InvalidParameter(String::from(
+                "wm_pos, wm_margin, wm_opacity and wm_scale require wm",
+            )));
+        }
+
+        Ok(transform)
+    }
+
+    // Parses a query string, eg. one produced by `to_query`
+    pub fn from_query(query: &str) -> Result<Self, Error> {
+        let params: Vec<(String, String)> = query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                (
+                    RawStr::new(name).url_decode_lossy().into_owned(),
+                    RawStr::new(value).url_decode_lossy().into_owned(),
+                )
+            })
+            .collect();
+
+        Transform::parse(
+            params
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        )
+    }
+
+    // Canonical query string with the parameters that differ from the defaults, in a fixed order
+    pub fn to_query(&self) -> String {
+        let default = Transform::default();
+        let mut params = vec![];
+
+        if let Some(rect) = self.rect {
+            params.push(format!("rect={}", rect.name()));
+        }
+        if let Some(threshold) = self.trim {
+            params.push(format!("trim={}", threshold));
+        }
+        if let Some(width) = self.width {
+            params.push(format!("w={}", width));
+        }
+        if let Some(height) = self.height {
+            params.push(format!("h={}", height));
+        }
+        if self.fit != default.fit {
+            params.push(format!("fit={}", self.fit.name()));
+        }
+        if self.format != default.format {
+            params.push(format!("fm={}", self.format.extension()));
+        }
+        if let Some(frame) = self.frame {
+            params.push(format!("frame={}", frame));
+        }
+        if let Some(page) = self.page {
+            params.push(format!("page={}", page));
+        }
+        if let Some(density) = self.density {
+            params.push(format!("density={}", density));
+        }
+        if self.rotation != default.rotation {
+            params.push(format!("rot={}", self.rotation));
+        }
+        if let Some(flip) = self.flip {
+            params.push(format!("flip={}", flip.name()));
+        }
+        if let Some(metadata) = self.metadata {
+            params.push(format!("meta={}", metadata.name()));
+        }
+        let adjustments = [
+            ("sharp", self.sharpen),
+            ("blur", self.blur),
+            ("bri", self.brightness),
+            ("con", self.contrast),
+            ("sat", self.saturation),
+            ("gam", self.gamma),
+        ];
+        for (name, value) in adjustments {
+            if let Some(value) = value {
+                params.push(format!("{}={}", name, value));
+            }
+        }
+        if let Some(watermark) = &self.watermark {
+            params.push(format!("wm={}", watermark));
+        }
+        if let Some(position) = self.watermark_position {
+            params.push(format!("wm_pos={}", position.name()));
+        }
+        if let Some(margin) = self.watermark_margin {
+            params.push(format!("wm_margin={}", margin));
+        }
+        if let Some(opacity) = self.watermark_opacity {
+            params.push(format!("wm_opacity={}", opacity));
+        }
+        if let Some(scale) = self.watermark_scale {
+            params.push(format!("wm_scale={}", scale));
+        }
+        if let Some(text) = &self.text {
+            params.push(format!(
+                "txt={}",
+                RawStr::new(text).percent_encode().as_str()
+            ));
+        }
+        if self.text_font != default.text_font {
+            params.push(format!("txt_font={}", self.text_font.name()));
+        }
+        if let Some(size) = self.text_size {
+            params.push(format!("txt_size={}", size));
+        }
+        if let Some(color) = self.text_color {
+            params.push(format!("txt_color={}", color.name()));
+        }
+        if let Some(position) = self.text_position {
+            params.push(format!("txt_pos={}", position.name()));
+        }
+        if let Some(width) = self.text_width {
+            params.push(format!("txt_width={}", width));
+        }
+        if let Some(padding) = self.padding {
+            params.push(format!("pad={}", padding));
+        }
+        if let Some(background) = self.background {
+            params.push(format!("bg={}", background.name()));
+        }
+        if let Some(border) = self.border {
+            params.push(format!("border={}", border.name()));
+        }
+        if let Some(radius) = self.radius {
+            params.push(format!("radius={}", radius));
+        }
+        if let Some(mask) = self.mask {
+            params.push(format!("mask={}", mask.name()));
+        }
+        if let Some(palette) = self.palette {
+            params.push(format!("palette={}", palette));
+        }
+
+        params.join("&")
+    }
+
+    // Whether the output needs an alpha channel, for rounded corners that the background doesn't
+    // fill or for a translucent background. Transparent padding is flattened like transparent
+    // sources are.
+    pub fn needs_transparency(&self) -> bool {
+        match self.background {
+            Some(background) => !background.is_opaque(),
+            None => self.radius.is_some() || self.mask.is_some(),
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Transform::default()
+    }
+
+    // Identifies the output in the cache. Equivalent transforms have the same id.
+    pub fn cache_id(&self) -> String {
+        let digest = Sha256::digest(self.to_query().as_bytes());
+        digest[..16].iter().fold(String::new(), |mut id, byte| {
+            let _ = write!(id, "{:02x}", byte);
+            id
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn parses_parameters() {
+        let transform = Transform::from_query("w=300&h=200&fit=cover&fm=jpg&frame=0&v=2").unwrap();
+        assert_eq!(
+            transform,
+            Transform {
+                width: Some(300),
+                height: Some(200),
+                fit: Fit::Cover,
+                format: OutputFormat::Jpeg,
+                frame: Some(0),
+                ..Transform::default()
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert!(Transform::from_query("w=0").is_err());
+        assert!(Transform::from_query("h=100000").is_err());
+        assert!(Transform::from_query("fit=squash").is_err());
+        assert!(Transform::from_query("fm=bmp").is_err());
+        assert!(Transform::from_query("frame=-1").is_err());
+    }
+
+    #[test]
+    fn canonicalizes_queries() {
+        let transform = Transform::from_query("fm=webp&h=200&w=300&fit=inside").unwrap();
+        assert_eq!(transform.to_query(), "w=300&h=200");
+        assert_eq!(
+            Transform::from_query(&transform.to_query()).unwrap(),
+            transform
+        );
+
+        let reordered = Transform::from_query("w=300&h=200").unwrap();
+        assert_eq!(reordered.cache_id(), transform.cache_id());
+        assert_eq!(transform.cache_id().len(), 32);
+    }
+
+    #[test]
+    fn parses_jpeg_xl() {
+        let transform = Transform::from_query("fm=jxl").unwrap();
+        assert_eq!(transform.format, OutputFormat::Jxl);
+        assert_eq!(transform.to_query(), "fm=jxl");
+    }
+
+    #[test]
+    fn parses_pages_and_density() {
+        let transform = Transform::from_query("page=2&density=144").unwrap();
+        assert_eq!((transform.page, transform.density), (Some(2), Some(144)));
+        assert_eq!(transform.to_query(), "page=2&density=144");
+
+        assert!(Transform::from_query("page=0").is_err());
+        assert!(Transform::from_query("density=1200").is_err());
+    }
+
+    #[test]
+    fn parses_rotation_and_flips() {
+        let transform = Transform::from_query("flip=v&rot=270").unwrap();
+        assert_eq!(transform.rotation, 270);
+        assert_eq!(transform.flip, Some(Flip::Vertical));
+        assert_eq!(transform.to_query(), "rot=270&flip=v");
+        assert_eq!(
+            Transform::from_query("rot=90&flip=h").unwrap().to_query(),
+            "rot=90&flip=h"
+        );
+        // No rotation is the default
+        assert!(Transform::from_query("rot=0").unwrap().is_default());
+
+        assert!(Transform::from_query("rot=45").is_err());
+        assert!(Transform::from_query("rot=360").is_err());
+        assert!(Transform::from_query("flip=x").is_err());
+    }
+
+    #[test]
+    fn parses_metadata_policies() {
+        let metadata = |query| Transform::from_query(query).unwrap().metadata;
+        assert_eq!(metadata("meta=strip"), Some(Metadata::Strip));
+        assert_eq!(metadata("meta=copyright"), Some(Metadata::Copyright));
+        assert_eq!(metadata("meta=icc"), Some(Metadata::Icc));
+        assert_eq!(metadata("w=100"), None);
+        assert_eq!(
+            Transform::from_query("meta=icc&w=100").unwrap().to_query(),
+            "w=100&meta=icc"
+        );
+
+        assert!(Transform::from_query("meta=exif").is_err());
+    }
+
+    #[test]
+    fn parses_adjustments() {
+        let transform =
+            Transform::from_query("gam=2.2&sat=-100&con=10.5&bri=5&blur=1&sharp=0.5").unwrap();
+        assert_eq!(transform.sharpen, Some(0.5));
+        assert_eq!(transform.saturation, Some(-100.0));
+        assert_eq!(
+            transform.to_query(),
+            "sharp=0.5&blur=1&bri=5&con=10.5&sat=-100&gam=2.2"
+        );
+        // Values that change nothing are left out
+        assert!(Transform::from_query("bri=0&gam=1.0").unwrap().is_default());
+
+        assert!(Transform::from_query("sharp=20").is_err());
+        assert!(Transform::from_query("blur=0.1").is_err());
+        assert!(Transform::from_query("bri=101").is_err());
+        assert!(Transform::from_query("sat=-150").is_err());
+        assert!(Transform::from_query("gam=NaN").is_err());
+    }
+
+    #[test]
+    fn parses_watermarks() {
+        let transform =
+            Transform::from_query("wm_scale=0.25&wm_opacity=0.5&wm_margin=0&wm_pos=nw&wm=logo")
+                .unwrap();
+        assert_eq!(transform.watermark.as_deref(), Some("logo"));
+        assert_eq!(transform.watermark_position, Some(Position::Nw));
+        assert_eq!(
+            transform.to_query(),
+            "wm=logo&wm_pos=nw&wm_margin=0&wm_opacity=0.5&wm_scale=0.25"
+        );
+
+        assert!(Transform::from_query("wm=../logo").is_err());
+        assert!(Transform::from_query("wm=logo&wm_pos=top").is_err());
+        assert!(Transform::from_query("wm=logo&wm_margin=2000").is_err());
+        assert!(Transform::from_query("wm=logo&wm_opacity=1.5").is_err());
+        assert!(Transform::from_query("wm=logo&wm_scale=0").is_err());
+        // Options need a watermark to apply to
+        assert!(Transform::from_query("wm_opacity=0.5").is_err());
+    }
+
+    #[test]
+    fn parses_text() {
+        let transform =
+            Transform::from_query("txt=Tom%20%26%20Jerry%3D1&txt_color=%23F80&txt_font=serif-bold")
+                .unwrap();
+        assert_eq!(transform.text.as_deref(), Some("Tom & Jerry=1"));
+        assert_eq!(
+            transform.text_color,
+            Some(Color {
+                red: 255,
+                green: 136,
+                blue: 0,
+                alpha: 255
+            })
+        );
+        assert_eq!(
+            transform.to_query(),
+            "txt=Tom%20%26%20Jerry%3D1&txt_font=serif-bold&txt_color=ff8800"
+        );
+        assert_eq!(
+            Transform::from_query(&transform.to_query()).unwrap(),
+            transform
+        );
+        assert_eq!(
+            Transform::from_query("txt=a&txt_color=00000080&txt_size=48&txt_pos=n&txt_width=600")
+                .unwrap()
+                .to_query(),
+            "txt=a&txt_size=48&txt_color=00000080&txt_pos=n&txt_width=600"
+        );
+
+        assert!(Transform::from_query("txt=").is_err());
+        assert!(Transform::from_query(&format!("txt={}", "a".repeat(501))).is_err());
+        assert!(Transform::from_query("txt=a&txt_font=comic").is_err());
+        assert!(Transform::from_query("txt=a&txt_size=1000").is_err());
+        assert!(Transform::from_query("txt=a&txt_color=red").is_err());
+        assert!(Transform::from_query("txt=a&txt_color=12345").is_err());
+        assert!(Transform::from_query("txt_size=48").is_err());
+    }
+
+    #[test]
+    fn parses_padding_borders_and_masks() {
+        assert_eq!(
+            Transform::from_query("mask=circle&radius=12&border=4&bg=rgba(255,%20136,0,0.5)&pad=0")
+                .unwrap()
+                .to_query(),
+            "bg=ff880080&border=4,000000&radius=12&mask=circle"
+        );
+        assert_eq!(
+            Transform::from_query("pad=10&bg=%23fff&border=2,rgb(255,0,0)")
+                .unwrap()
+                .to_query(),
+            "pad=10&bg=ffffff&border=2,ff0000"
+        );
+
+        assert!(Transform::from_query("pad=2000").is_err());
+        assert!(Transform::from_query("bg=rgba(255,0,0)").is_err());
+        assert!(Transform::from_query("bg=rgba(255,0,0,2)").is_err());
+        assert!(Transform::from_query("bg=rgb(256,0,0)").is_err());
+        assert!(Transform::from_query("border=0").is_err());
+        assert!(Transform::from_query("border=4,blue").is_err());
+        assert!(Transform::from_query("radius=0").is_err());
+        assert!(Transform::from_query("mask=star").is_err());
+    }
+
+    #[test]
+    fn falls_back_from_jpeg_for_transparency() {
+        let format = |query| Transform::from_query(query).unwrap().format;
+        assert_eq!(format("fm=jpg&radius=8"), OutputFormat::Png);
+        assert_eq!(format("fm=jpg&mask=circle&bg=fff"), OutputFormat::Jpeg);
+        assert_eq!(format("fm=jpg&pad=8&bg=ffffff80"), OutputFormat::Png);
+        assert_eq!(format("fm=jpg&pad=8"), OutputFormat::Jpeg);
+        assert_eq!(format("fm=avif&mask=circle"), OutputFormat::Avif);
+    }
+
+    #[test]
+    fn parses_crops_and_trims() {
+        let transform = Transform::from_query("w=100&trim=10&rect=10,%2020,300,200").unwrap();
+        assert_eq!(transform.trim, Some(10));
+        assert_eq!(transform.to_query(), "rect=10,20,300,200&trim=10&w=100");
+
+        assert!(Transform::from_query("rect=0,0,0,10").is_err());
+        assert!(Transform::from_query("rect=0,0,10").is_err());
+        assert!(Transform::from_query("rect=-1,0,10,10").is_err());
+        assert!(Transform::from_query("trim=0").is_err());
+        assert!(Transform::from_query("trim=300").is_err());
+    }
+
+    #[test]
+    fn parses_palettes() {
+        let transform = Transform::from_query("palette=8&fm=json&w=100").unwrap();
+        assert_eq!(transform.palette, Some(8));
+        assert_eq!(transform.to_query(), "w=100&fm=json&palette=8");
+
+        assert!(Transform::from_query("fm=json&palette=0").is_err());
+        assert!(Transform::from_query("fm=json&palette=17").is_err());
+        // Palettes are only returned as JSON
+        assert!(Transform::from_query("palette=5").is_err());
+        assert!(Transform::from_query("fm=png&palette=5").is_err());
+    }
+
+    #[test]
+    fn negotiates_jpeg_xl_when_enabled() {
+        let accept = Accept::from_str("image/jxl,image/avif,image/webp,*/*;q=0.8").unwrap();
+        let enabled = Negotiation { _jxl: true };
+
+        assert_eq!(enabled.negotiate(Some(&accept)), Some(OutputFormat::Jxl));
+        assert_eq!(
+            enabled.negotiate(Some(&Accept::from_str("image/webp").unwrap())),
+            None
+        );
+        assert_eq!(enabled.negotiate(None), None);
+        assert_eq!(Negotiation::default().negotiate(Some(&accept)), None);
+    }
+
+    #[test]
+    fn defaults_without_parameters() {
+        assert!(Transform::from_query("").unwrap().is_default());
+        assert!(Transform::from_query("fm=webp&v=2").unwrap().is_default());
+        assert!(!Transform::from_query("frame=0").unwrap().is_default());
+    }
+}
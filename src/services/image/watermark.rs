@@ -0,0 +1,244 @@
+use super::pipeline;
+use super::transform::{Position, Transform};
+use crate::error::Error;
+use crate::services::storage::Storage;
+use libvips::ops::{self, BandFormat};
+use libvips::VipsImage;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+
+fn failed(error: libvips::error::Error) -> Error {
+    Error::DecodeFailed(format!("Could not apply watermark: {}", error))
+}
+
+fn full_opacity() -> f64 {
+    1.0
+}
+
+// Watermark that requests apply by name, so that clients can't overlay arbitrary images
+#[derive(Clone, Debug, Deserialize)]
+pub struct Preset {
+    // Key of the watermark image in the source bucket
+    pub key: String,
+    #[serde(default)]
+    pub position: Position,
+    // Pixels between the watermark and the edges of the image
+    #[serde(default)]
+    pub margin: u32,
+    #[serde(default = "full_opacity")]
+    pub opacity: f64,
+    // Width of the watermark relative to the width of the image. Watermarks keep their own size
+    // when it isn't set.
+    pub scale: Option<f64>,
+}
+
+// Presets configured in `WATERMARKS` as a JSON object by name, eg.
+// `{"logo": {"key": "watermarks/logo.png", "position": "se", "margin": 16}}`
+pub fn presets_from_env() -> HashMap<String, Preset> {
+    let value = match env::var("WATERMARKS") {
+        Ok(value) if !value.trim().is_empty() => value,
+        _ => return HashMap::new(),
+    };
+
+    match serde_json::from_str::<HashMap<String, Preset>>(&value) {
+        Ok(presets) => presets,
+        Err(error) => {
+            log::error!("Ignoring WATERMARKS as it isn't valid. {}", error);
+            HashMap::new()
+        }
+    }
+}
+
+// Preset of a transform with the overrides of the request applied, along with its image
+pub struct Watermark {
+    pub image: Vec<u8>,
+    pub position: Position,
+    pub margin: u32,
+    pub opacity: f64,
+    pub scale: Option<f64>,
+}
+
+impl Watermark {
+    // Factor that the overlay is scaled by on images of this size, or none when the margins leave
+    // no room for a pixel of it
+    fn factor(
+        &self,
+        overlay_width: f64,
+        overlay_height: f64,
+        width: i32,
+        height: i32,
+    ) -> Option<f64> {
+        let margin = self.margin as f64;
+        let factor = self
+            .scale
+            .map_or(1.0, |scale| width as f64 * scale / overlay_width)
+            .min((width as f64 - 2.0 * margin) / overlay_width)
+            .min((height as f64 - 2.0 * margin) / overlay_height);
+        if factor * overlay_width < 1.0 || factor * overlay_height < 1.0 {
+            return None;
+        }
+        Some(factor)
+    }
+
+    pub async fn load(
+        transform: &Transform,
+        presets: &HashMap<String, Preset>,
+        storage: &Storage,
+    ) -> Result<Option<Self>, Error> {
+        let name = match &transform.watermark {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let preset = presets
+            .get(name)
+            .ok_or_else(|| Error::InvalidParameter(format!("Unknown watermark {}", name)))?;
+
+        // A missing watermark is a configuration error rather than a missing source image.
+        // Watermarks are configured rather than uploaded, so their size isn't limited.
+        let image = storage
+            .read(&preset.key, usize::MAX)
+            .await
+            .map_err(|error| match error {
+                Error::NotFound(_) => {
+                    Error::Internal(format!("Could not find watermark image {}", preset.key))
+                }
+                error => error,
+            })?;
+
+        Ok(Some(Watermark {
+            image,
+            position: transform.watermark_position.unwrap_or(preset.position),
+            margin: transform.watermark_margin.unwrap_or(preset.margin),
+            opacity: transform
+                .watermark_opacity
+                .unwrap_or(preset.opacity)
+                .clamp(0.0, 1.0),
+            scale: transform.watermark_scale.or(preset.scale),
+        }))
+    }
+
+    // Sizes the watermark for images of this size and applies its opacity. Watermarks are shrunk
+    // to fit within the margins, and skipped when the margins leave no room for them.
+    pub fn prepare(
+        &self,
+        overlay: VipsImage,
+        width: i32,
+        height: i32,
+    ) -> Result<Option<VipsImage>, Error> {
+        let factor = match self.factor(
+            overlay.get_width() as f64,
+            overlay.get_height() as f64,
+            width,
+            height,
+        ) {
+            Some(factor) => factor,
+            None => return Ok(None),
+        };
+
+        let overlay = if overlay.image_hasalpha() {
+            overlay
+        } else {
+            ops::bandjoin_const(&overlay, &mut [255.0]).map_err(failed)?
+        };
+        let overlay = pipeline::scale(overlay, factor, factor)?;
+        if self.opacity == 1.0 {
+            return Ok(Some(overlay));
+        }
+
+        let bands = overlay.get_bands() as usize;
+        let mut factors = vec![1.0; bands - 1];
+        factors.push(self.opacity);
+        let opacity = VipsImage::new_from_image(&overlay, &factors).map_err(failed)?;
+        ops::multiply(&overlay, &opacity)
+            .and_then(|faded| ops::cast(&faded, BandFormat::Uchar))
+            .map(Some)
+            .map_err(failed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watermark(margin: u32, opacity: f64, scale: Option<f64>) -> Watermark {
+        Watermark {
+            image: vec![],
+            position: Position::default(),
+            margin,
+            opacity,
+            scale,
+        }
+    }
+
+    #[test]
+    fn sizes_watermarks_within_the_margins() {
+        // Watermarks keep their size when they fit
+        assert_eq!(
+            watermark(16, 1.0, None).factor(200.0, 100.0, 1000, 800),
+            Some(1.0)
+        );
+        // They are shrunk to fit between the margins, whichever side is tighter
+        assert_eq!(
+            watermark(50, 1.0, None).factor(200.0, 100.0, 300, 800),
+            Some(1.0)
+        );
+        assert_eq!(
+            watermark(50, 1.0, None).factor(200.0, 100.0, 200, 800),
+            Some(0.5)
+        );
+        assert_eq!(
+            watermark(0, 1.0, None).factor(200.0, 100.0, 1000, 25),
+            Some(0.25)
+        );
+        // Scaled relative to the width of the image, up or down
+        assert_eq!(
+            watermark(0, 1.0, Some(0.5)).factor(200.0, 100.0, 1000, 800),
+            Some(2.5)
+        );
+        assert_eq!(
+            watermark(0, 1.0, Some(0.1)).factor(200.0, 100.0, 1000, 800),
+            Some(0.5)
+        );
+        assert_eq!(
+            watermark(100, 1.0, Some(0.5)).factor(200.0, 100.0, 300, 800),
+            Some(0.5)
+        );
+    }
+
+    #[test]
+    fn skips_watermarks_without_room() {
+        assert_eq!(
+            watermark(50, 1.0, None).factor(200.0, 100.0, 100, 800),
+            None
+        );
+        assert_eq!(watermark(50, 1.0, None).factor(200.0, 100.0, 80, 800), None);
+        // Less than a pixel would be left of the height
+        assert_eq!(watermark(0, 1.0, None).factor(200.0, 10.0, 10, 800), None);
+    }
+
+    #[test]
+    fn fades_prepared_watermarks() {
+        let overlay =
+            VipsImage::new_from_memory(&[200; 8 * 4 * 3], 8, 4, 3, BandFormat::Uchar).unwrap();
+
+        let prepared = watermark(0, 0.5, None)
+            .prepare(overlay, 4, 100)
+            .unwrap()
+            .unwrap();
+        assert_eq!((prepared.get_width(), prepared.get_height()), (4, 2));
+        // An alpha band is added for the opacity
+        assert_eq!(prepared.get_bands(), 4);
+        let pixels = prepared.image_write_to_memory();
+        assert!(pixels
+            .chunks(4)
+            .all(|pixel| pixel[3] == 127 || pixel[3] == 128));
+
+        let overlay =
+            VipsImage::new_from_memory(&[200; 8 * 4 * 3], 8, 4, 3, BandFormat::Uchar).unwrap();
+        assert!(watermark(4, 0.5, None)
+            .prepare(overlay, 8, 100)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -22,3 +22,15 @@ pub async fn initialize() -> Result<()> {
     rocket::tokio::spawn(future);
     Ok(())
 }
+
+// Rocket logs to the console for the server. Binaries that don't launch rocket log to the console
+// with env_logger instead, unless datadog is configured.
+pub async fn initialize_standalone() -> Result<()> {
+    if env::var("DATADOG_API_KEY").is_ok() {
+        return initialize().await;
+    }
+
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .try_init()?;
+    Ok(())
+}
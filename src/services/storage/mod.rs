@@ -1,6 +1,7 @@
 use crate::drivers::S3;
 pub use crate::drivers::S3::UploadData;
-use anyhow::{anyhow, Result};
+use crate::error::Error;
+use anyhow::Result;
 use aws_sdk_s3::{error::GetObjectError, types::SdkError, Client};
 use std::env;
 
@@ -10,38 +11,92 @@ pub struct Storage {
     _dest: String,
 }
 
+// Missing objects are NotFound, anything else means S3 couldn't be reached or refused the request
+fn from_read_error(key: &str, error: anyhow::Error) -> Error {
+    if let Some(SdkError::ServiceError { err, .. }) =
+        error.downcast_ref::<SdkError<GetObjectError>>()
+    {
+        if err.is_no_such_key() {
+            return Error::NotFound(format!("Could not find {}", key));
+        }
+    }
+
+    log::error!("Could not read object: {:?}", error);
+    Error::UpstreamUnavailable(format!("Could not read {}", key))
+}
+
 impl Storage {
-    pub async fn read(&self, key: &str) -> Result<Vec<u8>, SdkError<GetObjectError>> {
-        let result = S3::fetch_object(&self._client, &self._source, &key).await;
+    // Objects larger than `max_bytes` are rejected using their content length, before their
+    // body is downloaded
+    pub async fn read(&self, key: &str, max_bytes: usize) -> Result<Vec<u8>, Error> {
+        let object = S3::get_object(&self._client, &self._source, key)
+            .await
+            .map_err(|error| from_read_error(key, error.into()))?;
+
+        let bytes = object.content_length().max(0) as usize;
+        if bytes > max_bytes {
+            return Err(Error::TooLarge(format!(
+                "Image is {} bytes, which is larger than {} bytes",
+                bytes, max_bytes
+            )));
+        }
+
+        S3::read_body(object)
+            .await
+            .map_err(|error| from_read_error(key, error))
+    }
+
+    pub async fn read_from_cache(&self, key: &str) -> Result<Vec<u8>, Error> {
+        S3::fetch_object(&self._client, &self._dest, key)
+            .await
+            .map_err(|error| from_read_error(key, error))
+    }
 
+    pub async fn write(&self, key: &str, value: UploadData) -> Result<(), Error> {
+        let result = S3::upload_object(&self._client, &self._dest, key, value).await;
         match result {
-            Ok(data) => Ok(data),
+            Ok(()) => Ok(()),
             Err(error) => {
-                log::error!("Could not read object: {:?}", error);
-                Err(error)
+                log::error!("{:?}", error);
+                Err(Error::UpstreamUnavailable(format!(
+                    "Could not write {}",
+                    key
+                )))
             }
         }
     }
 
-    pub async fn read_from_cache(&self, key: &str) -> Result<Vec<u8>> {
-        let result = S3::fetch_object(&self._client, &self._dest, &key).await;
-
+    pub async fn delete(&self, key: &str) -> Result<(), Error> {
+        let result = S3::delete_object(&self._client, &self._dest, key).await;
         match result {
-            Ok(data) => Ok(data),
-            Err(_) => Err(anyhow!("Could not read object from cache")),
+            Ok(()) => Ok(()),
+            Err(error) => {
+                log::error!("{:?}", error);
+                Err(Error::UpstreamUnavailable(format!(
+                    "Could not delete {}",
+                    key
+                )))
+            }
         }
     }
 
-    pub async fn write(&self, key: &str, value: UploadData) -> Result<()> {
-        let result = S3::upload_object(&self._client, &self._dest, &key, value).await;
+    pub async fn list_cache(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let result = S3::list_objects(&self._client, &self._dest, prefix).await;
         match result {
-            Ok(()) => Ok(()),
+            Ok(keys) => Ok(keys),
             Err(error) => {
                 log::error!("{:?}", error);
-                Err(anyhow!("Could not write object"))
+                Err(Error::UpstreamUnavailable(format!(
+                    "Could not list {}",
+                    prefix
+                )))
             }
         }
     }
+
+    pub fn is_source(&self, bucket: &str) -> bool {
+        self._source == bucket
+    }
 }
 
 pub async fn initialize() -> Result<Storage> {
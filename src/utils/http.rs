@@ -1,7 +1,7 @@
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::ContentType;
 use rocket::http::Header;
-use rocket::response::{Responder, Response};
+use rocket::response::{self, Responder, Response};
 use rocket::Request;
 
 // Fairing for setting CORS Headers
@@ -70,3 +70,19 @@ impl TextResponse {
         }
     }
 }
+
+// Responder that adds `Vary: Accept` to responses whose format was picked from the Accept
+// header, so that shared caches keep a copy for each format
+pub struct Negotiated<R> {
+    pub inner: R,
+    pub vary: bool,
+}
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Negotiated<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        let mut response = self.inner.respond_to(request)?;
+        if self.vary {
+            response.set_header(Header::new("Vary", "Accept"));
+        }
+        Ok(response)
+    }
+}
@@ -1,23 +1,46 @@
 use anyhow::{anyhow, Result};
+use std::env;
+use std::str::FromStr;
 
 pub mod http;
 
+// Value of the environment variable, or the default when it isn't set or can't be parsed
+pub fn read_env<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+// Only dots in the file name start an extension, so keys like `2022.01/photo` have none
+fn split_ext(path: &str) -> Option<(&str, &str)> {
+    let file_name_start = path.rfind('/').map_or(0, |index| index + 1);
+    match path[file_name_start..].rsplit_once('.') {
+        Some((name, ext)) if !name.is_empty() => Some((&path[..file_name_start + name.len()], ext)),
+        _ => None,
+    }
+}
+
 pub fn get_path_without_ext(path: &str) -> &str {
-    match path.rsplit_once('.') {
+    match split_ext(path) {
         Some((new_path, _)) => new_path,
         None => path,
     }
 }
 
 pub fn get_ext_from_path(path: &str) -> Option<&str> {
-    match path.rsplit_once('.') {
+    match split_ext(path) {
         Some((_, ext)) => Some(ext),
         None => None,
     }
 }
 
+// Used to skip keys by their extension before the file is read. The format of files that are
+// read is detected from their contents instead.
 pub fn is_allowed_type(ext: &str) -> Result<()> {
-    let allowed_ext = ["jpeg", "avif", "jpg", "jpeg", "png", "webp"];
+    let allowed_ext = [
+        "jpeg", "avif", "jpg", "jpeg", "png", "webp", "gif", "heic", "heif", "jxl", "svg", "pdf",
+    ];
     if allowed_ext.contains(&ext) {
         return Ok(());
     } else {